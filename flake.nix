@@ -52,10 +52,11 @@
             "freedesktop-icons-0.4.0" = "sha256-bETYaRkI9TRdRVy1FmrPlbTxsygafTqZ+7e4Q6v4h8w=";
           };
 
-          nativeBuildInputs = with pkgs; [makeWrapper];
+          # pkg-config finds libpulse for libpulse-binding
+          nativeBuildInputs = with pkgs; [makeWrapper pkg-config];
 
           # For Iced, modified based on Halloy's nixpkg, then wrap for runtime deps `sway` and `wpctl`
-          buildInputs = dlopenLibraries;
+          buildInputs = dlopenLibraries ++ [pkgs.libpulseaudio];
           postFixup = ''
             rpath=$(patchelf --print-rpath $out/bin/${name})
             patchelf --set-rpath "$rpath:${nixpkgs.lib.makeLibraryPath dlopenLibraries}" $out/bin/${name}
@@ -68,6 +69,8 @@
       devShells.default = pkgs.mkShell {
         packages = [
           toolchain
+          pkgs.pkg-config
+          pkgs.libpulseaudio
         ];
 
         # For Iced, https://github.com/iced-rs/iced/blob/master/DEPENDENCIES.md
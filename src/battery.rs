@@ -1,9 +1,8 @@
 use iced::futures::{SinkExt, Stream};
 use log::warn;
 
-use crate::POLL_RATE_MS;
-
-pub fn battery() -> impl Stream<Item = BatteryMessage> {
+pub fn battery(poll_rate_ms: &u64) -> impl Stream<Item = BatteryMessage> {
+    let poll_rate_ms = *poll_rate_ms;
     iced::stream::channel(100, async move |mut output| {
         let Ok(manager) = starship_battery::Manager::new() else {
             warn!("Unable to get battery manager");
@@ -20,15 +19,18 @@ pub fn battery() -> impl Stream<Item = BatteryMessage> {
 
         tokio::task::spawn(async move {
             let mut interval =
-                tokio::time::interval(std::time::Duration::from_millis(POLL_RATE_MS));
+                tokio::time::interval(std::time::Duration::from_millis(poll_rate_ms));
             let mut old_state = None;
             loop {
                 let new_state = (&battery).into();
                 if old_state != Some(new_state) {
-                    output
+                    if output
                         .send(BatteryMessage::Update(new_state))
                         .await
-                        .expect("Unable to send update");
+                        .is_err()
+                    {
+                        break;
+                    }
 
                     old_state = Some(new_state);
                 }
@@ -51,16 +53,21 @@ pub enum BatteryMessage {
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct BatteryInfo {
     pub charge: u32,
+    pub charging: bool,
     pub icon: &'static str,
+    /// Nerd Font glyph for the same state as the icon
+    pub glyph: char,
 }
 
 impl From<&starship_battery::Battery> for BatteryInfo {
     fn from(battery: &starship_battery::Battery) -> Self {
-        // Get charge as a two-digit percent
-        let charge = (battery.state_of_charge().value * 100.0).floor() as u32;
+        // Get charge as a two-digit percent, some batteries report over 100%
+        let charge = ((battery.state_of_charge().value * 100.0).floor() as u32).min(100);
         Self {
             charge,
+            charging: battery.state() == starship_battery::State::Charging,
             icon: battery_icon(charge, battery.state()),
+            glyph: battery_glyph(charge, battery.state()),
         }
     }
 }
@@ -96,3 +103,37 @@ fn battery_icon(charge: u32, state: starship_battery::State) -> &'static str {
         ][index],
     }
 }
+
+fn battery_glyph(charge: u32, state: starship_battery::State) -> char {
+    let index = (charge / 10) as usize;
+    match state {
+        // nf-md-battery_charging_outline to nf-md-battery_charging_100
+        starship_battery::State::Charging => [
+            '\u{f089f}',
+            '\u{f089c}',
+            '\u{f0086}',
+            '\u{f0087}',
+            '\u{f0088}',
+            '\u{f089d}',
+            '\u{f0089}',
+            '\u{f089e}',
+            '\u{f008a}',
+            '\u{f008b}',
+            '\u{f0085}',
+        ][index],
+        // nf-md-battery_outline to nf-md-battery
+        _ => [
+            '\u{f008e}',
+            '\u{f007a}',
+            '\u{f007b}',
+            '\u{f007c}',
+            '\u{f007d}',
+            '\u{f007e}',
+            '\u{f007f}',
+            '\u{f0080}',
+            '\u{f0081}',
+            '\u{f0082}',
+            '\u{f0079}',
+        ][index],
+    }
+}
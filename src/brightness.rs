@@ -0,0 +1,77 @@
+use std::{path::Path, time::Duration};
+
+use iced::futures::{SinkExt, Stream};
+use log::{debug, warn};
+
+const BACKLIGHT_DIR: &str = "/sys/class/backlight";
+
+/// Polls the brightness of the first backlight, since sysfs doesn't notify
+/// of changes
+pub fn brightness(poll_rate_ms: &u64) -> impl Stream<Item = BrightnessMessage> {
+    let poll_rate = Duration::from_millis(*poll_rate_ms);
+    iced::stream::channel(100, async move |mut output| {
+        let backlight = match std::fs::read_dir(BACKLIGHT_DIR) {
+            Ok(mut entries) => entries.find_map(|entry| entry.ok()),
+            Err(err) => {
+                debug!("Unable to read {BACKLIGHT_DIR}: {err}");
+                None
+            }
+        };
+        let Some(backlight) = backlight else {
+            debug!("No backlight found");
+            return;
+        };
+        let path = backlight.path();
+        debug!("Using backlight {}", path.display());
+
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(poll_rate);
+            let mut old_state = None;
+            loop {
+                interval.tick().await;
+
+                let new_state = match read_brightness(&path).await {
+                    Ok(brightness) => brightness,
+                    Err(err) => {
+                        warn!("Unable to read backlight {}: {err}", path.display());
+                        break;
+                    }
+                };
+                if old_state != Some(new_state) {
+                    if output
+                        .send(BrightnessMessage::Update(new_state))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+
+                    old_state = Some(new_state);
+                }
+            }
+        });
+    })
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum BrightnessMessage {
+    /// Brightness as a percentage
+    Update(u32),
+}
+
+type BrightnessError = Box<dyn std::error::Error + Send + Sync>;
+
+async fn read_brightness(backlight: &Path) -> Result<u32, BrightnessError> {
+    let brightness = read_number(&backlight.join("brightness")).await?;
+    let max = read_number(&backlight.join("max_brightness")).await?;
+    if max == 0 {
+        return Err("Maximum brightness is 0".into());
+    }
+
+    Ok(((brightness as f32 / max as f32) * 100.0).round() as u32)
+}
+
+async fn read_number(path: &Path) -> Result<u32, BrightnessError> {
+    let contents = tokio::fs::read_to_string(path).await?;
+    Ok(contents.trim().parse()?)
+}
@@ -0,0 +1,485 @@
+use std::{collections::HashMap, fmt, path::PathBuf, time::Duration};
+
+use iced::{
+    Color, Theme,
+    futures::{SinkExt, Stream},
+};
+use log::{debug, error, info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::{Deserialize, Deserializer};
+
+use crate::POLL_RATE_MS;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Height of the bar in pixels
+    pub height: u32,
+    /// Width of the bar in pixels, defaults to the width of each output
+    pub width: Option<u32>,
+    pub text_size: f32,
+    /// Name of the default font
+    pub font: String,
+    /// Freedesktop icon theme, searched before hicolor
+    pub icon_theme: String,
+    /// Modules drawn with Nerd Font glyphs from the font rather than icons,
+    /// any of battery, volume, microphone, system and input
+    #[serde(deserialize_with = "deserialize_glyphs")]
+    pub glyphs: Vec<Module>,
+    pub theme: ThemeConfig,
+    pub modules: ModulesConfig,
+    pub workspaces: WorkspacesConfig,
+    pub window: WindowConfig,
+    pub input: InputConfig,
+    pub volume: VolumeConfig,
+    pub osd: OsdConfig,
+    pub tray: TrayConfig,
+    pub intervals: IntervalsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            height: 40,
+            width: None,
+            text_size: 20.0,
+            font: String::from("JetBrainsMono Nerd Font"),
+            icon_theme: String::from("Gruvbox-Plus-Dark"),
+            glyphs: Vec::new(),
+            theme: ThemeConfig::default(),
+            modules: ModulesConfig::default(),
+            workspaces: WorkspacesConfig::default(),
+            window: WindowConfig::default(),
+            input: InputConfig::default(),
+            volume: VolumeConfig::default(),
+            osd: OsdConfig::default(),
+            tray: TrayConfig::default(),
+            intervals: IntervalsConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Path of the config file, `$XDG_CONFIG_HOME/baelyks-bar/config.toml`
+    pub fn path() -> Option<PathBuf> {
+        Some(
+            dirs::config_dir()?
+                .join(env!("CARGO_PKG_NAME"))
+                .join("config.toml"),
+        )
+    }
+
+    /// Loads the config file, falling back to the default config if it does
+    /// not exist.
+    pub fn load() -> Result<Self, ConfigError> {
+        let Some(path) = Self::path() else {
+            info!("Unable to find the config dir, using the default config");
+            return Ok(Self::default());
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                info!("No config at {}, using the default config", path.display());
+                return Ok(Self::default());
+            }
+            Err(err) => return Err(ConfigError::Io(path, err)),
+        };
+
+        toml::from_str(&contents).map_err(|err| ConfigError::Parse(path, err))
+    }
+}
+
+/// Modules that can be drawn with glyphs
+const GLYPH_MODULES: [Module; 5] = [
+    Module::Battery,
+    Module::Volume,
+    Module::Microphone,
+    Module::System,
+    Module::Input,
+];
+
+fn deserialize_glyphs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Module>, D::Error> {
+    let modules = Vec::<Module>::deserialize(deserializer)?;
+    match modules
+        .iter()
+        .find(|module| !GLYPH_MODULES.contains(module))
+    {
+        Some(module) => {
+            let module = format!("{module:?}").to_lowercase();
+            Err(serde::de::Error::custom(format!(
+                "{module} can't be drawn with glyphs, only battery, volume, microphone, \
+                 system and input can"
+            )))
+        }
+        None => Ok(modules),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, err) => write!(f, "Unable to read {}: {}", path.display(), err),
+            Self::Parse(path, err) => write!(f, "Invalid config {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Watches the config file and reloads it whenever it changes.
+pub fn watch() -> impl Stream<Item = ConfigMessage> {
+    iced::stream::channel(100, async move |mut output| {
+        let Some(path) = Config::path() else {
+            warn!("Unable to find the config dir, not watching the config");
+            return;
+        };
+        let Some(dir) = path.parent() else {
+            warn!("Unable to get the config dir of {}", path.display());
+            return;
+        };
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let watcher = notify::recommended_watcher(move |event| {
+            // The receiver only goes away when the subscription is dropped
+            let _ = sender.send(event);
+        });
+        let mut watcher = match watcher {
+            Ok(watcher) => watcher,
+            Err(err) => {
+                warn!("Unable to create the config watcher: {err}");
+                return;
+            }
+        };
+        // Watch the directory rather than the file, since editors often
+        // replace the file instead of writing to it
+        if let Err(err) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+            warn!("Unable to watch {}: {err}", dir.display());
+            return;
+        }
+
+        while let Some(event) = receiver.recv().await {
+            let event: notify::Event = match event {
+                Ok(event) => event,
+                Err(err) => {
+                    warn!("Config watcher error: {err}");
+                    continue;
+                }
+            };
+            let relevant = matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            ) && event.paths.iter().any(|event_path| event_path == &path);
+            if !relevant {
+                continue;
+            }
+
+            // Saving usually produces a burst of events, only reload once
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            while receiver.try_recv().is_ok() {}
+
+            debug!("Reloading config {}", path.display());
+            let message = match Config::load() {
+                Ok(config) => ConfigMessage::Reloaded(config),
+                Err(err) => {
+                    error!("{err}");
+                    ConfigMessage::Error(err.to_string())
+                }
+            };
+            output.send(message).await.expect("Unable to send config");
+        }
+    })
+}
+
+#[derive(Debug, Clone)]
+pub enum ConfigMessage {
+    Reloaded(Config),
+    Error(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    pub background: HexColor,
+    pub text: HexColor,
+    pub primary: HexColor,
+    pub success: HexColor,
+    pub danger: HexColor,
+}
+
+impl Default for ThemeConfig {
+    fn default() -> Self {
+        let palette = iced::theme::Palette::GRUVBOX_DARK;
+        Self {
+            background: HexColor(palette.background),
+            text: HexColor(iced::color!(0xebdbb2)),
+            primary: HexColor(palette.primary),
+            success: HexColor(palette.success),
+            danger: HexColor(palette.danger),
+        }
+    }
+}
+
+impl ThemeConfig {
+    pub fn theme(&self) -> Theme {
+        Theme::custom(
+            "Bar".to_string(),
+            iced::theme::Palette {
+                background: self.background.0,
+                text: self.text.0,
+                primary: self.primary.0,
+                success: self.success.0,
+                danger: self.danger.0,
+                ..iced::theme::Palette::GRUVBOX_DARK
+            },
+        )
+    }
+}
+
+/// A color written as `#rrggbb` or `#rrggbbaa`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HexColor(pub Color);
+
+impl<'de> Deserialize<'de> for HexColor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        parse_hex(&hex)
+            .map(HexColor)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid color \"{hex}\"")))
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Color> {
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    if !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+
+    match hex.len() {
+        6 => Some(Color::from_rgb8(channel(0)?, channel(2)?, channel(4)?)),
+        8 => Some(Color::from_rgba8(
+            channel(0)?,
+            channel(2)?,
+            channel(4)?,
+            channel(6)? as f32 / 255.0,
+        )),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModulesConfig {
+    pub left: Vec<Module>,
+    pub center: Vec<Module>,
+    pub right: Vec<Module>,
+}
+
+impl Default for ModulesConfig {
+    fn default() -> Self {
+        Self {
+            left: vec![Module::Workspaces, Module::Mode],
+            center: vec![],
+            right: vec![
+                Module::Tray,
+                Module::System,
+                Module::Input,
+                Module::Microphone,
+                Module::Volume,
+                Module::Battery,
+                Module::Clock,
+            ],
+        }
+    }
+}
+
+impl ModulesConfig {
+    pub fn contains(&self, module: Module) -> bool {
+        [&self.left, &self.center, &self.right]
+            .iter()
+            .any(|section| section.contains(&module))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Module {
+    Workspaces,
+    Clock,
+    Battery,
+    Volume,
+    Microphone,
+    Tray,
+    System,
+    Input,
+    Window,
+    Mode,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkspacesConfig {
+    /// Show empty placeholders for workspaces 1 to 9 and 0
+    pub placeholders: bool,
+    /// Show workspace names instead of dots
+    pub labels: bool,
+    /// Wrap around when scrolling past the first or last workspace
+    pub scroll_wrap: bool,
+    /// Only scroll through the workspaces on the bar's output
+    pub scroll_same_output: bool,
+}
+
+impl Default for WorkspacesConfig {
+    fn default() -> Self {
+        Self {
+            placeholders: true,
+            labels: false,
+            scroll_wrap: true,
+            scroll_same_output: true,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    /// Maximum number of characters of the title to show
+    pub max_title_length: usize,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            max_title_length: 50,
+        }
+    }
+}
+
+/// How to show keyboard layouts, keyed by the sway layout name. Layouts
+/// without a label or icon are shown as a short code.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    pub labels: HashMap<String, String>,
+    pub icons: HashMap<String, String>,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            labels: HashMap::new(),
+            icons: HashMap::from([
+                (
+                    String::from("English (US)"),
+                    String::from("indicator-keyboard-En"),
+                ),
+                (
+                    String::from("Spanish"),
+                    String::from("indicator-keyboard-Es"),
+                ),
+            ]),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumeConfig {
+    /// Mouse button that opens the device and application popup
+    pub popup_button: MouseButton,
+    /// Percentage to change the volume by per scroll step
+    pub step: f32,
+    /// Maximum percentage to raise the volume to, above 100 overamplifies
+    pub max: f32,
+}
+
+impl Default for VolumeConfig {
+    fn default() -> Self {
+        Self {
+            popup_button: MouseButton::Right,
+            step: 1.0,
+            max: 100.0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+/// On-screen display shown when the volume changes from outside the bar or
+/// the backlight brightness changes
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OsdConfig {
+    pub enabled: bool,
+    /// Milliseconds before the display hides again
+    pub timeout: u64,
+}
+
+impl Default for OsdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout: 1500,
+        }
+    }
+}
+
+/// Tray items are matched by their StatusNotifierItem id
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrayConfig {
+    /// Run the tray, which also hosts the StatusNotifierWatcher if nothing
+    /// else does. Off by default, and the tray module only shows items then.
+    pub enabled: bool,
+    /// Items shown first, in this order, before the rest in arrival order
+    pub pinned: Vec<String>,
+    /// Items that are never shown
+    pub hidden: Vec<String>,
+}
+
+/// Polling intervals in milliseconds, which can't be 0
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntervalsConfig {
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub battery: u64,
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub system: u64,
+    /// Only used when falling back to wpctl
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub volume: u64,
+    /// Backlight polling for the OSD
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub brightness: u64,
+}
+
+fn deserialize_interval<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("interval must be at least 1 ms")),
+        interval => Ok(interval),
+    }
+}
+
+impl Default for IntervalsConfig {
+    fn default() -> Self {
+        Self {
+            battery: POLL_RATE_MS,
+            system: POLL_RATE_MS,
+            volume: POLL_RATE_MS,
+            brightness: POLL_RATE_MS,
+        }
+    }
+}
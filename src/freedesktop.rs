@@ -1,65 +1,121 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{LazyLock, Mutex},
+};
 
-use image::RgbaImage;
-use log::{trace, warn};
-use rand::Rng;
-use rand::distributions::Alphanumeric;
+use iced::widget::{image::Handle as ImageHandle, svg::Handle as SvgHandle};
+use image::{RgbaImage, imageops::FilterType};
+use log::{debug, trace, warn};
 use system_tray::item::IconPixmap;
 
-fn tmp_path() -> Option<PathBuf> {
-    let mut tries = 0;
-    while tries < 3 {
-        tries += 1;
+/// Picks the pixmap closest to `size` pixels tall, preferring larger ones
+/// since downscaling looks better than upscaling.
+pub fn best_pixmap(pixmaps: &[IconPixmap], size: u32) -> Option<&IconPixmap> {
+    let size = size as i32;
+    pixmaps
+        .iter()
+        .min_by_key(|pixmap| (pixmap.height < size, (pixmap.height - size).abs()))
+}
 
-        let filename: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(8)
-            .map(char::from)
-            .collect();
-        let path = PathBuf::from(format!("/tmp/{}.png", filename));
+/// Converts a StatusNotifierItem pixmap, which is ARGB32 in network byte
+/// order, into an in-memory RGBA image `size` pixels tall.
+pub fn image_from_pixmap(pixmap: &IconPixmap, size: u32) -> Option<ImageHandle> {
+    let (width, height) = (pixmap.width as u32, pixmap.height as u32);
+    if pixmap.pixels.len() != width as usize * height as usize * 4 {
+        warn!(
+            "Pixmap of {} bytes does not match its size {}x{}",
+            pixmap.pixels.len(),
+            width,
+            height
+        );
+        return None;
+    }
 
-        if path.try_exists().is_ok_and(|exists| !exists) {
-            return Some(path);
-        }
+    let mut pixels = pixmap.pixels.clone();
+    for pixel in pixels.chunks_exact_mut(4) {
+        // ARGB to RGBA
+        pixel.rotate_left(1);
     }
 
-    warn!("Unable to generate a temporary path");
-    None
+    let image = RgbaImage::from_raw(width, height, pixels)?;
+    // Rescale once here rather than in the renderer every frame
+    let image = if height != size && height > 0 {
+        let width = (width * size).div_ceil(height);
+        image::imageops::resize(&image, width, size, FilterType::Lanczos3)
+    } else {
+        image
+    };
+
+    Some(ImageHandle::from_rgba(
+        image.width(),
+        image.height(),
+        image.into_raw(),
+    ))
 }
 
-pub fn tmp_image_from_data(image_data: &IconPixmap) -> Option<PathBuf> {
-    // Generate a path in the /tmp directory
-    let path = tmp_path()?;
-
-    // Create and save the image
-    let Some(image) = RgbaImage::from_raw(
-        image_data.width as u32,
-        image_data.height as u32,
-        image_data.pixels.clone(),
-    ) else {
-        warn!("Failed to create RGBA image");
-        return None;
-    };
-    let save_result = image.save(&path);
+/// Theme searched after the configured one, which every theme inherits from
+const FALLBACK_THEME: &str = "hicolor";
 
-    if let Err(err) = save_result {
-        warn!(
-            "Failed to save image to {} with error {}",
-            path.display(),
-            err
-        );
-        return None;
+/// Resolved icons by name and size, including ones that weren't found, until
+/// the theme is set again
+#[derive(Default)]
+struct IconCache {
+    theme: String,
+    paths: HashMap<(String, u16), Option<PathBuf>>,
+    handles: HashMap<(String, u16), Option<IconHandle>>,
+}
+
+static CACHE: LazyLock<Mutex<IconCache>> = LazyLock::new(Mutex::default);
+
+/// A loaded icon, cheap to clone and reuse across renders
+#[derive(Debug, Clone)]
+pub enum IconHandle {
+    Svg(SvgHandle),
+    Image(ImageHandle),
+    /// A bundled icon, drawn in the text color of the theme
+    Bundled(SvgHandle),
+}
+
+/// Sets the icon theme searched first and clears the cache, so that icons
+/// installed since are found too
+pub fn set_theme(theme: &str) {
+    debug!("Using icon theme {theme}");
+    *CACHE.lock().unwrap() = IconCache {
+        theme: theme.to_string(),
+        ..Default::default()
     };
+}
 
-    Some(path)
+/// Gets a loaded icon for a freedesktop icon name, see [`find_icon_path`].
+/// Falls back to the bundled icon of that name if the theme has none.
+pub fn find_icon(icon_name: &str, size: u16) -> Option<IconHandle> {
+    let key = (icon_name.to_string(), size);
+    if let Some(handle) = CACHE.lock().unwrap().handles.get(&key) {
+        return handle.clone();
+    }
+
+    let handle = find_icon_path(icon_name, size).map(|path| {
+        if path.extension().is_some_and(|ext| ext == "svg") {
+            IconHandle::Svg(SvgHandle::from_path(path))
+        } else {
+            IconHandle::Image(ImageHandle::from_path(path))
+        }
+    });
+    let handle = handle.or_else(|| {
+        bundled(icon_name).map(|bytes| {
+            trace!("Using the bundled icon {icon_name}");
+            IconHandle::Bundled(SvgHandle::from_memory(bytes))
+        })
+    });
+    CACHE.lock().unwrap().handles.insert(key, handle.clone());
+    handle
 }
 
 /// Gets a path for an icon by first checking if the passed icon is a path that
-/// exists, and if not, searches for a matching freedesktop icon.
-pub fn find_icon_path(icon_name_or_path: &str) -> Option<PathBuf> {
-    /// Freedesktop Icon Theme name
-    const THEME: &str = "Gruvbox-Plus-Dark";
-
+/// exists, and if not, searches for a matching freedesktop icon of about `size`
+/// pixels, in the configured theme and then in hicolor.
+pub fn find_icon_path(icon_name_or_path: &str, size: u16) -> Option<PathBuf> {
     trace!("Checking path {icon_name_or_path}");
     // Paths are supposed to be prepended with "file://" but in practice many are not
     let path: PathBuf = icon_name_or_path.replace("file://", "").into();
@@ -67,22 +123,155 @@ pub fn find_icon_path(icon_name_or_path: &str) -> Option<PathBuf> {
         return Some(path);
     }
 
-    let icon = freedesktop_icons::lookup(icon_name_or_path)
-        .with_context("Status")
-        .with_cache()
-        .force_svg()
-        .with_theme(THEME)
-        .find()
-        .or(freedesktop_icons::lookup(icon_name_or_path)
-            .with_cache()
-            .force_svg()
-            .with_theme(THEME)
-            .find());
+    let key = (icon_name_or_path.to_string(), size);
+    let theme = {
+        let cache = CACHE.lock().unwrap();
+        if let Some(icon) = cache.paths.get(&key) {
+            return icon.clone();
+        }
+        cache.theme.clone()
+    };
 
+    let icon = lookup(icon_name_or_path, size, &theme);
     match &icon {
         Some(path) => trace!("Found icon {} at {}", icon_name_or_path, path.display()),
         None => trace!("Unable to find icon {}", icon_name_or_path),
     }
 
+    CACHE.lock().unwrap().paths.insert(key, icon.clone());
     icon
 }
+
+fn lookup(icon_name: &str, size: u16, theme: &str) -> Option<PathBuf> {
+    for theme in [theme, FALLBACK_THEME] {
+        if theme.is_empty() {
+            continue;
+        }
+        // Prefer scalable icons, then status icons, e.g. battery indicators
+        // rather than battery devices
+        for svg in [true, false] {
+            for context in [Some("Status"), None] {
+                // Not using the lookup cache, which can't be cleared
+                let mut lookup = freedesktop_icons::lookup(icon_name)
+                    .with_theme(theme)
+                    .with_size(size);
+                if let Some(context) = context {
+                    lookup = lookup.with_context(context);
+                }
+                if svg {
+                    lookup = lookup.force_svg();
+                }
+
+                if let Some(path) = lookup.find() {
+                    return Some(path);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+macro_rules! bundled_icons {
+    ($($name:literal),* $(,)?) => {
+        &[$(($name, include_bytes!(concat!("../icons/", $name, ".svg")) as &[u8])),*]
+    };
+}
+
+/// Icons shipped with the bar for every icon name the modules use, so they
+/// still show something without a matching icon theme installed. They are
+/// drawn in `currentColor` and recolored when rendered.
+const BUNDLED: &[(&str, &[u8])] = bundled_icons![
+    "battery-000",
+    "battery-010",
+    "battery-020",
+    "battery-030",
+    "battery-040",
+    "battery-050",
+    "battery-060",
+    "battery-070",
+    "battery-080",
+    "battery-090",
+    "battery-100",
+    "battery-000-charging",
+    "battery-010-charging",
+    "battery-020-charging",
+    "battery-030-charging",
+    "battery-040-charging",
+    "battery-050-charging",
+    "battery-060-charging",
+    "battery-070-charging",
+    "battery-080-charging",
+    "battery-090-charging",
+    "battery-100-charging",
+    "audio-volume-muted",
+    "audio-volume-low",
+    "audio-volume-medium",
+    "audio-volume-high",
+    "microphone-sensitivity-muted",
+    "microphone-sensitivity-high",
+    "indicator-cpufreq",
+    "indicator-cpufreq-25",
+    "indicator-cpufreq-50",
+    "indicator-cpufreq-75",
+    "indicator-cpufreq-100",
+    "indicator-keyboard-En",
+    "indicator-keyboard-Es",
+    "notifications",
+    "display-brightness",
+];
+
+/// Gets the SVG data of a bundled icon
+fn bundled(icon_name: &str) -> Option<&'static [u8]> {
+    BUNDLED
+        .iter()
+        .find(|(name, _)| *name == icon_name)
+        .map(|(_, bytes)| *bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixmap(width: i32, height: i32, pixels: &[u8]) -> IconPixmap {
+        IconPixmap {
+            width,
+            height,
+            pixels: pixels.to_vec(),
+        }
+    }
+
+    #[test]
+    fn argb_to_rgba() {
+        let pixmap = pixmap(2, 1, &[0xff, 0x10, 0x20, 0x30, 0x80, 0x40, 0x50, 0x60]);
+        let Some(ImageHandle::Rgba {
+            width,
+            height,
+            pixels,
+            ..
+        }) = image_from_pixmap(&pixmap, 1)
+        else {
+            panic!("Expected an RGBA image");
+        };
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(
+            &pixels[..],
+            &[0x10, 0x20, 0x30, 0xff, 0x40, 0x50, 0x60, 0x80]
+        );
+    }
+
+    #[test]
+    fn rescales_to_size() {
+        let pixmap = pixmap(1, 1, &[0xff, 0x10, 0x20, 0x30]);
+        let Some(ImageHandle::Rgba { width, height, .. }) = image_from_pixmap(&pixmap, 4) else {
+            panic!("Expected an RGBA image");
+        };
+        assert_eq!((width, height), (4, 4));
+    }
+
+    #[test]
+    fn size_mismatch() {
+        let pixmap = pixmap(2, 2, &[0xff, 0x10, 0x20, 0x30]);
+        assert!(image_from_pixmap(&pixmap, 2).is_none());
+    }
+}
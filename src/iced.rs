@@ -1,131 +1,358 @@
+use std::collections::HashMap;
+
 use chrono::Local;
 use iced::{
-    Element, Length, Subscription, Task, Theme,
-    widget::{self, Row, button, center_y, mouse_area, row, text},
+    Color, Element, Length, Subscription, Task, Theme,
+    theme::Palette,
+    widget::{self, Column, Row, button, center_y, mouse_area, row, text},
 };
 use iced_layershell::{
-    Settings, application, reexport::Anchor, settings::LayerShellSettings, to_layer_message,
+    Settings, daemon,
+    reexport::{Anchor, Layer, NewLayerShellSettings, OutputOption},
+    settings::{LayerShellSettings, StartMode},
+    to_layer_message,
 };
-use log::{debug, trace, warn};
+use log::{debug, info, trace, warn};
 
 use crate::{
-    POLL_RATE_MS,
     battery::{self, BatteryInfo, BatteryMessage},
-    sway::{InputInfo, SwayMessenger},
+    brightness::{self, BrightnessMessage},
+    config::{self, Config, ConfigMessage, Module, MouseButton},
+    freedesktop::{self, IconHandle},
+    sway::{InputInfo, OutputInfo, SwayMessenger, WindowInfo},
     system::{self, SystemInfo, SystemMessage},
-    tray::{TrayItems, TrayMessage},
-    volume::VolumeInfo,
+    tray::{self, MenuItem, Status, Toggle, TrayItem, TrayItems, TrayMessage, TrayMessenger},
+    volume::{Device, Mixer, VolumeInfo, VolumeMessage, VolumeMessenger},
 };
 use crate::{
-    sway::{self, SwayMessage, WorkspaceInfo},
+    sway::{self, ScrollOptions, SwayMessage, WorkspaceInfo, WorkspaceTarget},
     volume,
 };
 
-const HEIGHT: u32 = 40;
-const TEXT_SIZE: f32 = 20.0;
 const SMALL: f32 = 12.0;
 const MEDIUM: f32 = 24.0;
-const BIG: f32 = 36.0;
-
-pub fn run() -> Result<(), iced_layershell::Error> {
-    application(State::default, State::namespace, State::update, State::view)
-        .subscription(State::subscription)
-        .style(State::style)
-        .theme(State::theme)
-        .settings(Settings {
-            layer_settings: LayerShellSettings {
-                anchor: Anchor::Top,
-                size: Some((2880, HEIGHT)),
-                exclusive_zone: HEIGHT as i32,
-                ..Default::default()
-            },
-            default_font: iced::Font::with_name("JetBrainsMono Nerd Font"),
+/// Pixels of touchpad scrolling that count as one line of a mouse wheel
+const PIXELS_PER_LINE: f32 = 20.0;
+const OSD_SIZE: (u32, u32) = (360, 72);
+
+pub fn run(config: Config) -> Result<(), iced_layershell::Error> {
+    // The default font has to outlive the application
+    let font = iced::Font::with_name(config.font.clone().leak());
+    // Surfaces are created per output once sway reports them
+    let settings = Settings {
+        layer_settings: LayerShellSettings {
+            start_mode: StartMode::Background,
             ..Default::default()
-        })
-        .run()
+        },
+        default_font: font,
+        ..Default::default()
+    };
+
+    daemon(
+        move || State::new(config.clone(), font),
+        State::namespace,
+        State::update,
+        State::view,
+    )
+    .subscription(State::subscription)
+    .style(State::style)
+    .theme(State::theme)
+    .settings(settings)
+    .run()
 }
 
 #[derive(Default)]
 struct State {
+    config: Config,
+    /// The configured default font, which changing requires a restart
+    font: iced::Font,
+    config_error: Option<String>,
+    /// Bar surfaces and the outputs they are on
+    surfaces: HashMap<iced::window::Id, OutputInfo>,
     clock_hovered: bool,
     workspaces: Vec<WorkspaceInfo>,
     sway_messenger: Option<SwayMessenger>,
+    sway_connected: bool,
+    /// Scrolling that hasn't added up to a whole workspace switch yet
+    workspace_scroll: f32,
     battery: Option<BatteryInfo>,
     battery_hovered: bool,
     volume: Option<VolumeInfo>,
+    microphone: Option<VolumeInfo>,
+    mixer: Option<Mixer>,
+    volume_popup: Option<iced::window::Id>,
+    /// Scrolling that hasn't added up to a whole volume step yet
+    volume_scroll: f32,
+    volume_messenger: Option<VolumeMessenger>,
+    /// Hovering the volume or microphone, whose changes then already show
+    /// in the bar
+    volume_hovered: bool,
+    /// Backlight brightness as a percentage, only watched for the OSD
+    brightness: Option<u32>,
+    /// On-screen display surface and what it shows
+    osd: Option<(iced::window::Id, Osd)>,
+    /// Incremented whenever the OSD is shown, so only the latest hide applies
+    osd_generation: u64,
     tray_items: Option<TrayItems>,
+    tray_messenger: Option<TrayMessenger>,
+    tray_menu: Option<TrayMenu>,
+    /// Show passive tray items too
+    tray_expanded: bool,
     system_info: Option<SystemInfo>,
     system_hovered: bool,
     input: Option<InputInfo>,
+    window: Option<WindowInfo>,
+    /// The active sway binding mode, `None` in the default mode
+    mode: Option<String>,
 }
 
 #[to_layer_message(multi)]
 #[derive(Clone, Debug)]
 enum Message {
     Tick,
+    Config(ConfigMessage),
     ClockHover(bool),
     Sway(SwayMessage),
-    SwitchWorkspace(i32),
+    SwitchWorkspace(WorkspaceTarget),
+    WorkspaceScroll(Option<String>, iced::mouse::ScrollDelta),
+    NextLayout,
     Battery(BatteryMessage),
+    Brightness(BrightnessMessage),
     BatteryHover(bool),
-    Volume(Option<VolumeInfo>),
-    VolumeToggleMute,
-    VolumeScroll(iced::mouse::ScrollDelta),
+    Volume(VolumeMessage),
+    VolumeToggleMute(Device),
+    VolumeScroll(Device, iced::mouse::ScrollDelta),
+    VolumePopup,
+    VolumeHover(bool),
+    OsdHide(u64),
+    SetDefaultSink(String),
+    SetStreamVolume(u32, u32),
+    ToggleStreamMute(u32),
     Tray(TrayMessage),
+    TrayActivate(String),
+    TraySecondaryActivate(String),
+    TrayMenu(String),
+    /// Opens a submenu of the tray menu, or goes back with `None`
+    TrayMenuOpen(Option<i32>),
+    TrayMenuClicked(i32),
+    TrayExpand,
     System(SystemMessage),
     SystemHover(bool),
 }
 
-fn icon(icon: &str) -> Option<Element<Message>> {
-    let icon = crate::freedesktop::find_icon_path(icon)?;
-    Some(
-        widget::svg(icon)
-            .width(Length::Fixed(BIG))
-            .height(Length::Fixed(BIG))
+/// What the on-screen display shows
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Osd {
+    Volume(Device),
+    Brightness,
+}
+
+/// An open tray item context menu
+struct TrayMenu {
+    id: iced::window::Id,
+    destination: String,
+    /// Ids of the open submenus
+    submenus: Vec<i32>,
+}
+
+/// Shows an icon from the icon theme, or a glyph if it can't be found
+fn icon<'a>(icon: &str, size: f32) -> Element<'a, Message> {
+    try_icon(icon, size).unwrap_or_else(|| missing_icon(size))
+}
+
+fn try_icon<'a>(icon: &str, size: f32) -> Option<Element<'a, Message>> {
+    let icon = freedesktop::find_icon(icon, size as u16)?;
+    Some(icon_handle(&icon, size))
+}
+
+fn icon_handle<'a>(icon: &IconHandle, size: f32) -> Element<'a, Message> {
+    match icon {
+        IconHandle::Svg(handle) => widget::svg(handle.clone())
+            .width(Length::Fixed(size))
+            .height(Length::Fixed(size))
             .into(),
-    )
+        IconHandle::Image(handle) => widget::image(handle.clone())
+            .width(Length::Fixed(size))
+            .height(Length::Fixed(size))
+            .into(),
+        IconHandle::Bundled(handle) => widget::svg(handle.clone())
+            .width(Length::Fixed(size))
+            .height(Length::Fixed(size))
+            .style(|theme: &Theme, _| widget::svg::Style {
+                color: Some(theme.palette().text),
+            })
+            .into(),
+    }
+}
+
+/// Adds a scroll to the scrolling that hasn't added up to a whole line yet,
+/// and takes the whole lines out of it, positive when scrolling up
+fn scroll_steps(scroll: &mut f32, delta: iced::mouse::ScrollDelta) -> i32 {
+    // Wheels scroll up with positive lines, touchpads follow the content with
+    // negative pixels
+    *scroll += match delta {
+        iced::mouse::ScrollDelta::Lines { y, .. } => y,
+        iced::mouse::ScrollDelta::Pixels { y, .. } => -y / PIXELS_PER_LINE,
+    };
+
+    let steps = scroll.trunc();
+    *scroll -= steps;
+    steps as i32
+}
+
+/// Shows a Nerd Font glyph from the bar's font in the space of a `size` icon
+fn glyph<'a>(glyph: char, size: f32, color: fn(&Palette) -> Color) -> Element<'a, Message> {
+    let style = move |theme: &Theme| widget::text::Style {
+        color: Some(color(&theme.palette())),
+    };
+    widget::center(text(glyph.to_string()).size(size * 0.75).style(style))
+        .width(Length::Fixed(size))
+        .height(Length::Fixed(size))
+        .into()
+}
+
+fn missing_icon<'a>(size: f32) -> Element<'a, Message> {
+    widget::center(text("󰘥").size(size * 0.75))
+        .width(Length::Fixed(size))
+        .height(Length::Fixed(size))
+        .into()
 }
 
 impl State {
+    fn new(config: Config, font: iced::Font) -> Self {
+        freedesktop::set_theme(&config.icon_theme);
+        Self {
+            config,
+            font,
+            ..Default::default()
+        }
+    }
+
     fn namespace() -> String {
         String::from("Bar")
     }
 
-    fn workspaces(&self) -> Element<Message> {
-        center_y(
-            Row::from_iter(self.workspaces.iter().map(|info| {
-                button("")
-                    .on_press(Message::SwitchWorkspace(info.num))
-                    .style(|theme: &Theme, _| iced::widget::button::Style {
+    /// Creates, resizes and removes bar surfaces to match the sway outputs
+    fn update_surfaces(&mut self, outputs: Vec<OutputInfo>) -> Task<Message> {
+        let mut tasks = Vec::new();
+
+        self.surfaces.retain(|&id, surface| {
+            let connected = outputs.iter().any(|output| output.name == surface.name);
+            if !connected {
+                info!("Removing bar from output {}", surface.name);
+                tasks.push(Task::done(Message::RemoveWindow(id)));
+            }
+            connected
+        });
+
+        let height = self.config.height;
+        let width = |output: &OutputInfo| self.config.width.unwrap_or(output.width);
+        for output in outputs {
+            let size = (width(&output), height);
+            match self
+                .surfaces
+                .iter_mut()
+                .find(|(_, surface)| surface.name == output.name)
+            {
+                Some((&id, surface)) => {
+                    if *surface != output {
+                        tasks.push(Task::done(Message::SizeChange { id, size }));
+                        *surface = output;
+                    }
+                }
+                None => {
+                    info!("Adding bar to output {}", output.name);
+                    let id = iced::window::Id::unique();
+                    let settings = NewLayerShellSettings {
+                        anchor: Anchor::Top,
+                        size: Some(size),
+                        exclusive_zone: Some(height as i32),
+                        output_option: OutputOption::OutputName(output.name.clone()),
+                        ..Default::default()
+                    };
+                    tasks.push(Task::done(Message::NewLayerShell { settings, id }));
+                    self.surfaces.insert(id, output);
+                }
+            }
+        }
+
+        Task::batch(tasks)
+    }
+
+    fn workspaces(&self, output: Option<&str>) -> Element<Message> {
+        // Only show the workspaces on this output
+        let workspaces = self
+            .workspaces
+            .iter()
+            .filter(|info| output.is_none() || info.output.as_deref() == output);
+        let workspaces = if self.config.workspaces.placeholders {
+            sway::with_placeholders(workspaces)
+        } else {
+            workspaces.cloned().collect()
+        };
+        let labels = self.config.workspaces.labels;
+
+        // Grey out the workspaces while disconnected from sway
+        let connected = self.sway_connected;
+        let dim = move |color: iced::Color| {
+            if connected {
+                color
+            } else {
+                color.scale_alpha(0.3)
+            }
+        };
+
+        let workspaces = center_y(
+            Row::from_iter(workspaces.into_iter().map(|info| {
+                let label = if labels {
+                    text(info.name.clone()).size(self.config.text_size)
+                } else {
+                    text("")
+                };
+                let button = button(label)
+                    .on_press_maybe(connected.then(|| Message::SwitchWorkspace(info.target())))
+                    .style(move |theme: &Theme, _| iced::widget::button::Style {
                         background: if info.urgent {
-                            Some(theme.palette().danger.into())
+                            Some(dim(theme.palette().danger).into())
                         } else if info.focused {
-                            Some(theme.palette().primary.into())
+                            Some(dim(theme.palette().primary).into())
                         } else if info.nonempty {
-                            Some(theme.palette().text.into())
+                            Some(dim(theme.palette().text).into())
                         } else {
                             None
                         },
-                        border: iced::Border::default()
-                            .width(2)
-                            .rounded(3)
-                            .color(if info.urgent {
+                        border: iced::Border::default().width(2).rounded(3).color(dim(
+                            if info.urgent {
                                 theme.palette().danger
                             } else if info.focused || info.visible {
                                 theme.palette().primary
                             } else {
                                 theme.palette().text
-                            }),
+                            },
+                        )),
+                        // Keep labels readable on filled buttons
+                        text_color: dim(if info.urgent || info.focused || info.nonempty {
+                            theme.palette().background
+                        } else {
+                            theme.palette().text
+                        }),
                         ..Default::default()
-                    })
-                    .width(SMALL)
-                    .height(SMALL)
-                    .into()
+                    });
+
+                if labels {
+                    button.padding([0.0, SMALL / 2.0]).into()
+                } else {
+                    button.width(SMALL).height(SMALL).into()
+                }
             }))
             .spacing(MEDIUM)
             .padding([0.0, MEDIUM]),
-        )
-        .into()
+        );
+
+        let output = output.map(String::from);
+        mouse_area(workspaces)
+            .on_scroll(move |delta| Message::WorkspaceScroll(output.clone(), delta))
+            .into()
     }
 
     fn clock(&self) -> Element<Message> {
@@ -142,21 +369,70 @@ impl State {
         };
 
         mouse_area(
-            center_y(text(time.to_string()).size(TEXT_SIZE).style(style)).padding([0.0, SMALL]),
+            center_y(
+                text(time.to_string())
+                    .size(self.config.text_size)
+                    .style(style),
+            )
+            .padding([0.0, SMALL]),
         )
         .on_enter(Message::ClockHover(true))
         .on_exit(Message::ClockHover(false))
         .into()
     }
 
+    /// Shows the indicator of a module as a glyph if configured, and as an
+    /// icon otherwise
+    fn indicator<'a>(
+        &self,
+        module: Module,
+        icon_name: &str,
+        glyph_char: char,
+        color: fn(&Palette) -> Color,
+    ) -> Element<'a, Message> {
+        // The same size as the icons
+        if self.config.glyphs.contains(&module) {
+            glyph(glyph_char, self.icon_size(), color)
+        } else {
+            icon(icon_name, self.icon_size())
+        }
+    }
+
+    /// Size of module icons and glyphs, filling most of the bar's height
+    fn icon_size(&self) -> f32 {
+        (self.config.height as f32 * 0.9).round()
+    }
+
+    fn volume_indicator<'a>(&self, device: Device, info: VolumeInfo) -> Element<'a, Message> {
+        let module = match device {
+            Device::Sink => Module::Volume,
+            Device::Source => Module::Microphone,
+        };
+        let color: fn(&Palette) -> Color = if info.muted {
+            |palette| palette.danger
+        } else {
+            |palette| palette.text
+        };
+        self.indicator(module, info.icon, info.glyph, color)
+    }
+
     fn battery(&self) -> Option<Element<Message>> {
         let info = self.battery?;
-        let battery_icon = icon(info.icon)?;
+        let color: fn(&Palette) -> Color = if info.charging {
+            |palette| palette.success
+        } else if info.charge <= 20 {
+            |palette| palette.danger
+        } else {
+            |palette| palette.text
+        };
+        let battery_icon = self.indicator(Module::Battery, info.icon, info.glyph, color);
 
         let content = Row::new()
             .push(center_y(battery_icon))
             .push_maybe(if self.battery_hovered {
-                Some(center_y(text(format!("{}%", info.charge)).size(TEXT_SIZE)))
+                Some(center_y(
+                    text(format!("{}%", info.charge)).size(self.config.text_size),
+                ))
             } else {
                 None
             })
@@ -173,63 +449,439 @@ impl State {
     fn volume(&self) -> Option<Element<Message>> {
         let info = self.volume?;
 
-        let icon = icon(info.icon)?;
+        let icon = self.volume_indicator(Device::Sink, info);
+
+        let area = mouse_area(
+            row![
+                center_y(icon),
+                center_y(text(format!("{:>3}%", info.volume)).size(self.config.text_size))
+            ]
+            .padding([0.0, SMALL]),
+        )
+        .on_scroll(|delta| Message::VolumeScroll(Device::Sink, delta))
+        .on_enter(Message::VolumeHover(true))
+        .on_exit(Message::VolumeHover(false));
+
+        let area = match self.config.volume.popup_button {
+            MouseButton::Left => area.on_press(Message::VolumePopup),
+            MouseButton::Right => area
+                .on_press(Message::VolumeToggleMute(Device::Sink))
+                .on_right_press(Message::VolumePopup),
+            MouseButton::Middle => area
+                .on_press(Message::VolumeToggleMute(Device::Sink))
+                .on_middle_press(Message::VolumePopup),
+        };
+
+        Some(area.into())
+    }
+
+    fn volume_popup_size(&self) -> (u32, u32) {
+        let rows = self.mixer.as_ref().map_or(1, |mixer| {
+            // Headers and entries
+            2 + mixer.sinks.len() + mixer.streams.len()
+        });
+        let row_height = self.config.text_size * 2.0 + SMALL;
+        (480, (rows as f32 * row_height + 2.0 * MEDIUM) as u32)
+    }
+
+    fn volume_popup(&self) -> Element<Message> {
+        let Some(mixer) = &self.mixer else {
+            return widget::center(text("Switching devices requires PulseAudio")).into();
+        };
+        let text_size = self.config.text_size;
+
+        let sinks = Column::from_iter(mixer.sinks.iter().map(|sink| {
+            let label = row![
+                text(if sink.default { "󰄬" } else { " " }).size(text_size),
+                text(sink.description.clone()).size(text_size),
+            ]
+            .spacing(SMALL);
+
+            button(label)
+                .on_press(Message::SetDefaultSink(sink.name.clone()))
+                .style(button::text)
+                .width(Length::Fill)
+                .into()
+        }));
+
+        let streams = Column::from_iter(mixer.streams.iter().map(|stream| {
+            let index = stream.index;
+            row![
+                button(text(if stream.muted { "󰝟" } else { "󰕾" }).size(text_size))
+                    .on_press(Message::ToggleStreamMute(index))
+                    .style(button::text),
+                text(stream.name.clone())
+                    .size(text_size)
+                    .width(Length::Fill),
+                widget::slider(0..=100, stream.volume.min(100), move |volume| {
+                    Message::SetStreamVolume(index, volume)
+                })
+                .width(150),
+                text(format!("{:>3}%", stream.volume)).size(text_size),
+            ]
+            .spacing(SMALL)
+            .align_y(iced::Alignment::Center)
+            .into()
+        }));
+
+        Column::new()
+            .push(text("Output").size(text_size))
+            .push(sinks)
+            .push_maybe((!mixer.streams.is_empty()).then(|| text("Applications").size(text_size)))
+            .push(streams)
+            .spacing(SMALL)
+            .padding(MEDIUM)
+            .into()
+    }
+
+    /// Shows the OSD, or keeps it open if it is already shown
+    fn show_osd(&mut self, osd: Osd) -> Task<Message> {
+        self.osd_generation += 1;
+        let generation = self.osd_generation;
+        let timeout = iced::time::Duration::from_millis(self.config.osd.timeout);
+        let hide = Task::perform(tokio::time::sleep(timeout), move |_| {
+            Message::OsdHide(generation)
+        });
+
+        match &mut self.osd {
+            Some((_, shown)) => {
+                *shown = osd;
+                hide
+            }
+            None => {
+                let id = iced::window::Id::unique();
+                self.osd = Some((id, osd));
+                // Without anchors the compositor centers the surface
+                let settings = NewLayerShellSettings {
+                    anchor: Anchor::empty(),
+                    layer: Layer::Overlay,
+                    size: Some(OSD_SIZE),
+                    ..Default::default()
+                };
+                Task::batch([Task::done(Message::NewLayerShell { settings, id }), hide])
+            }
+        }
+    }
+
+    fn osd(&self, osd: Osd) -> Element<Message> {
+        let (indicator, value, max) = match osd {
+            Osd::Volume(device) => {
+                let info = match device {
+                    Device::Sink => self.volume,
+                    Device::Source => self.microphone,
+                };
+                let Some(info) = info else {
+                    return widget::Space::new(Length::Fill, Length::Fill).into();
+                };
+                // Leave room for overamplification
+                let max = self.config.volume.max.max(100.0);
+                (self.volume_indicator(device, info), info.volume, max)
+            }
+            Osd::Brightness => {
+                let Some(brightness) = self.brightness else {
+                    return widget::Space::new(Length::Fill, Length::Fill).into();
+                };
+                (
+                    icon("display-brightness", self.icon_size()),
+                    brightness,
+                    100.0,
+                )
+            }
+        };
+
+        Row::new()
+            .push(indicator)
+            .push(widget::progress_bar(0.0..=max, value as f32))
+            .push(text(format!("{value:>3}%")).size(self.config.text_size))
+            .spacing(SMALL)
+            .padding(MEDIUM)
+            .align_y(iced::Alignment::Center)
+            .into()
+    }
+
+    fn microphone(&self) -> Option<Element<Message>> {
+        // Hidden without a capture device
+        let info = self.microphone?;
 
         Some(
-            mouse_area(
-                row![
-                    center_y(icon),
-                    center_y(text(format!("{:>3}%", info.volume)).size(TEXT_SIZE))
-                ]
-                .padding([0.0, SMALL]),
-            )
-            .on_press(Message::VolumeToggleMute)
-            .on_scroll(Message::VolumeScroll)
-            .into(),
+            mouse_area(center_y(self.volume_indicator(Device::Source, info)).padding([0.0, SMALL]))
+                .on_press(Message::VolumeToggleMute(Device::Source))
+                .on_scroll(|delta| Message::VolumeScroll(Device::Source, delta))
+                .on_enter(Message::VolumeHover(true))
+                .on_exit(Message::VolumeHover(false))
+                .into(),
         )
     }
 
     fn tray(&self) -> Option<Element<Message>> {
+        // Items are left over after disabling the tray in the config
+        if !self.config.tray.enabled {
+            return None;
+        }
         let Some(items) = &self.tray_items else {
             return None;
         };
+        let config = &self.config.tray;
+        let items = || items.ordered(&config.pinned, &config.hidden);
+
+        // Passive items are hidden behind the expander
+        let passive = items().any(|(_, item)| matches!(item.status, Status::Passive));
+        let expander = passive.then(|| {
+            button(text(if self.tray_expanded { "󰅂" } else { "󰅁" }).size(self.config.text_size))
+                .on_press(Message::TrayExpand)
+                .style(button::text)
+        });
+
+        let items = items()
+            .filter(|(_, item)| self.tray_expanded || !matches!(item.status, Status::Passive));
 
         Some(
             center_y(
-                Row::from_iter(items.values().map(|item| {
-                    let icon: Element<Message> =
-                        if item.icon.extension().is_some_and(|ext| ext == "svg") {
-                            widget::svg(item.icon.clone()).into()
+                Row::new()
+                    .push_maybe(expander.map(center_y))
+                    .extend(items.map(|(destination, item)| {
+                        let attention = matches!(item.status, Status::NeedsAttention);
+                        let icon = match &item.attention_icon {
+                            Some(attention_icon) if attention => Some(attention_icon),
+                            _ => item.icon.as_ref(),
+                        };
+                        let size = self.config.height as f32 / 2.0;
+                        let icon =
+                            icon.map_or_else(|| missing_icon(size), |icon| icon_handle(icon, size));
+                        // Make items that need attention stand out
+                        let icon =
+                            widget::container(icon)
+                                .padding(2)
+                                .style(move |theme: &Theme| widget::container::Style {
+                                    border: if attention {
+                                        iced::Border::default()
+                                            .width(2)
+                                            .rounded(3)
+                                            .color(theme.palette().danger)
+                                    } else {
+                                        iced::Border::default()
+                                    },
+                                    ..Default::default()
+                                });
+
+                        let primary = if item.item_is_menu {
+                            Message::TrayMenu(destination.clone())
                         } else {
-                            widget::image(item.icon.clone()).into()
+                            Message::TrayActivate(destination.clone())
                         };
+                        let icon = mouse_area(icon)
+                            .on_press(primary)
+                            .on_middle_press(Message::TraySecondaryActivate(destination.clone()))
+                            .on_right_press(Message::TrayMenu(destination.clone()));
 
-                    widget::tooltip(icon, text(item.title.clone()), Default::default()).into()
-                }))
-                .height(HEIGHT as f32 / 2.0),
+                        widget::tooltip(
+                            icon,
+                            self.tray_tooltip(item),
+                            widget::tooltip::Position::Bottom,
+                        )
+                        .into()
+                    }))
+                    .spacing(SMALL / 2.0)
+                    .height(self.config.height as f32 / 2.0),
             )
             .into(),
         )
     }
 
+    fn tray_tooltip(&self, item: &TrayItem) -> Element<Message> {
+        let text_size = self.config.text_size;
+        // Fall back to the item title without a structured tooltip
+        let Some(tooltip) = item
+            .tooltip
+            .as_ref()
+            .filter(|tooltip| !tooltip.title.is_empty() || !tooltip.description.is_empty())
+        else {
+            return widget::container(text(item.title.clone()).size(text_size))
+                .padding(SMALL)
+                .style(widget::container::rounded_box)
+                .into();
+        };
+
+        let title = Row::new()
+            .push_maybe(tooltip.icon.as_ref().map(|icon| icon_handle(icon, MEDIUM)))
+            .push_maybe((!tooltip.title.is_empty()).then(|| {
+                text(tooltip.title.clone())
+                    .size(text_size)
+                    .font(iced::Font {
+                        weight: iced::font::Weight::Bold,
+                        ..self.font
+                    })
+            }))
+            .spacing(SMALL)
+            .align_y(iced::Alignment::Center);
+
+        let description = (!tooltip.description.is_empty()).then(|| {
+            let spans: Vec<widget::text::Span<'_, (), iced::Font>> = tooltip
+                .description
+                .iter()
+                .map(|span| {
+                    widget::span(span.text.as_str()).font(iced::Font {
+                        weight: if span.bold {
+                            iced::font::Weight::Bold
+                        } else {
+                            iced::font::Weight::Normal
+                        },
+                        style: if span.italic {
+                            iced::font::Style::Italic
+                        } else {
+                            iced::font::Style::Normal
+                        },
+                        ..self.font
+                    })
+                })
+                .collect();
+            widget::rich_text(spans).size(text_size * 0.8)
+        });
+
+        widget::container(
+            Column::new()
+                .push(title)
+                .push_maybe(description)
+                .spacing(SMALL / 2.0),
+        )
+        .padding(SMALL)
+        .style(widget::container::rounded_box)
+        .into()
+    }
+
+    /// Height of tray icons in pixels on the densest output
+    fn tray_icon_size(&self) -> u32 {
+        let scale = self
+            .surfaces
+            .values()
+            .map(|output| output.scale)
+            .fold(1.0, f32::max);
+        (self.config.height as f32 / 2.0 * scale).round() as u32
+    }
+
+    /// Has the tray resolve its icons again at the current icon size
+    fn refresh_tray_icons(&mut self) {
+        let size = self.tray_icon_size();
+        if let Some(tray_messenger) = &mut self.tray_messenger {
+            tray_messenger.set_icon_size(size);
+        }
+    }
+
+    /// Entries of the open tray menu level
+    fn tray_menu_entries(&self) -> Option<&[MenuItem]> {
+        let menu = self.tray_menu.as_ref()?;
+        let item = self.tray_items.as_ref()?.get(&menu.destination)?;
+        tray::submenu(&item.menu, &menu.submenus)
+    }
+
+    fn tray_menu_size(&self) -> (u32, u32) {
+        let entries = self.tray_menu_entries().map_or(1, |entries| entries.len());
+        // Leave room for the back entry in submenus
+        let back = self
+            .tray_menu
+            .as_ref()
+            .is_some_and(|menu| !menu.submenus.is_empty());
+        let rows = entries + back as usize;
+        let row_height = self.config.text_size * 2.0;
+        (320, (rows as f32 * row_height + 2.0 * SMALL) as u32)
+    }
+
+    fn tray_menu(&self) -> Element<Message> {
+        let Some(entries) = self.tray_menu_entries() else {
+            return widget::center(text("No menu")).into();
+        };
+        let text_size = self.config.text_size;
+
+        let back = self
+            .tray_menu
+            .as_ref()
+            .is_some_and(|menu| !menu.submenus.is_empty())
+            .then(|| {
+                button(text("󰅁 Back").size(text_size))
+                    .on_press(Message::TrayMenuOpen(None))
+                    .style(button::text)
+                    .width(Length::Fill)
+            });
+
+        let entries = entries.iter().map(|entry| -> Element<Message> {
+            match entry {
+                MenuItem::Seperator => {
+                    widget::container(widget::Space::new(Length::Fill, Length::Fixed(1.0)))
+                        .padding([SMALL / 2.0, 0.0])
+                        .style(|theme: &Theme| widget::container::Style {
+                            background: Some(theme.palette().text.scale_alpha(0.3).into()),
+                            ..Default::default()
+                        })
+                        .into()
+                }
+                MenuItem::Item {
+                    id,
+                    label,
+                    enabled,
+                    toggle,
+                    submenu,
+                } => {
+                    let toggle = match toggle {
+                        Some(Toggle::Checkmark(true)) => "󰄵",
+                        Some(Toggle::Checkmark(false)) => "󰄱",
+                        Some(Toggle::Radio(true)) => "󰐾",
+                        Some(Toggle::Radio(false)) => "󰐽",
+                        None => " ",
+                    };
+                    let content = row![
+                        text(toggle).size(text_size),
+                        text(label.clone()).size(text_size).width(Length::Fill),
+                    ]
+                    .push_maybe((!submenu.is_empty()).then(|| text("󰅂").size(text_size)))
+                    .spacing(SMALL);
+
+                    let message = if submenu.is_empty() {
+                        Message::TrayMenuClicked(*id)
+                    } else {
+                        Message::TrayMenuOpen(Some(*id))
+                    };
+                    button(content)
+                        .on_press_maybe(enabled.then_some(message))
+                        .style(button::text)
+                        .width(Length::Fill)
+                        .into()
+                }
+            }
+        });
+
+        Column::new()
+            .push_maybe(back)
+            .extend(entries)
+            .padding(SMALL)
+            .into()
+    }
+
     fn system(&self) -> Option<Element<Message>> {
         let info = self.system_info?;
 
-        let cpu_icon = if info.cpu <= 20.0 {
-            icon("indicator-cpufreq")
+        // Glyphs are nf-md-circle_outline and nf-md-circle_slice_2 to 8
+        let (cpu_icon, cpu_glyph) = if info.cpu <= 20.0 {
+            ("indicator-cpufreq", '\u{f0766}')
         } else if info.cpu <= 40.0 {
-            icon("indicator-cpufreq-25")
+            ("indicator-cpufreq-25", '\u{f0a9f}')
         } else if info.cpu <= 60.0 {
-            icon("indicator-cpufreq-50")
+            ("indicator-cpufreq-50", '\u{f0aa1}')
         } else if info.cpu <= 80.0 {
-            icon("indicator-cpufreq-75")
+            ("indicator-cpufreq-75", '\u{f0aa3}')
         } else {
-            icon("indicator-cpufreq-100")
-        }?;
+            ("indicator-cpufreq-100", '\u{f0aa5}')
+        };
+        let color: fn(&Palette) -> Color = if info.cpu > 80.0 {
+            |palette| palette.danger
+        } else {
+            |palette| palette.text
+        };
+        let cpu_icon = self.indicator(Module::System, cpu_icon, cpu_glyph, color);
 
         let row = if self.system_hovered {
             Row::new().push(center_y(
-                text(format!("{:>2.0}% {:>2.0}%", info.memory, info.cpu)).size(TEXT_SIZE),
+                text(format!("{:>2.0}% {:>2.0}%", info.memory, info.cpu))
+                    .size(self.config.text_size),
             ))
         } else {
             Row::new()
@@ -245,33 +897,183 @@ impl State {
     }
 
     fn input(&self) -> Option<Element<Message>> {
+        let input = self.input.as_ref()?;
+        let config = &self.config.input;
+
+        let short_code =
+            || -> Element<Message> { text(input.short_code()).size(self.config.text_size).into() };
+        let content: Element<Message> = match config.labels.get(&input.layout) {
+            Some(label) => text(label.clone()).size(self.config.text_size).into(),
+            // nf-md-keyboard followed by the layout, since there are no
+            // glyphs for individual layouts
+            None if self.config.glyphs.contains(&Module::Input) => row![
+                center_y(glyph('\u{f030c}', self.icon_size(), |palette| palette.text)),
+                center_y(short_code()),
+            ]
+            .spacing(SMALL / 2.0)
+            .into(),
+            None => config
+                .icons
+                .get(&input.layout)
+                .and_then(|name| try_icon(name, self.icon_size()))
+                .unwrap_or_else(short_code),
+        };
+
         Some(
-            center_y(icon(self.input?.icon)?)
-                .padding([0.0, SMALL])
+            mouse_area(center_y(content).padding([0.0, SMALL]))
+                .on_press(Message::NextLayout)
                 .into(),
         )
     }
 
-    fn view(&self) -> Element<Message> {
-        let left = row![self.workspaces()];
+    fn window(&self) -> Option<Element<Message>> {
+        let window = self.window.as_ref()?;
 
-        let right = Row::new()
-            .spacing(SMALL)
-            .push_maybe(self.tray())
-            .push_maybe(self.system())
-            .push_maybe(self.input())
-            .push_maybe(self.volume())
-            .push_maybe(self.battery())
-            .push(self.clock());
-        let right = widget::right(right);
+        let max_length = self.config.window.max_title_length;
+        let title = if window.title.chars().count() > max_length {
+            let mut title: String = window
+                .title
+                .chars()
+                .take(max_length.saturating_sub(1))
+                .collect();
+            title.push('…');
+            title
+        } else {
+            window.title.clone()
+        };
 
-        row![left, right].width(Length::Fill).into()
+        // The same size as the tray icons
+        let icon_size = self.config.height as f32 / 2.0;
+        let content = Row::new()
+            .push_maybe(
+                window
+                    .icon
+                    .as_ref()
+                    .and_then(|path| {
+                        freedesktop::find_icon(&path.to_string_lossy(), icon_size as u16)
+                    })
+                    .map(|icon| center_y(icon_handle(&icon, icon_size))),
+            )
+            .push(center_y(text(title).size(self.config.text_size)))
+            .spacing(SMALL);
+
+        Some(center_y(content).padding([0.0, SMALL]).into())
+    }
+
+    fn mode(&self) -> Option<Element<Message>> {
+        let mode = self.mode.as_ref()?;
+
+        let pill = widget::container(text(mode.clone()).size(self.config.text_size))
+            .padding([0.0, SMALL])
+            .style(|theme: &Theme| widget::container::Style {
+                background: Some(theme.palette().primary.into()),
+                text_color: Some(theme.palette().background),
+                border: iced::Border::default().rounded(SMALL),
+                ..Default::default()
+            });
+
+        Some(center_y(pill).padding([0.0, SMALL]).into())
+    }
+
+    fn config_error(&self) -> Option<Element<Message>> {
+        let error = self.config_error.as_ref()?;
+
+        // nf-md-alert_circle
+        let indicator = text("\u{f0028}")
+            .size(self.config.text_size)
+            .style(|theme: &Theme| widget::text::Style {
+                color: Some(theme.palette().danger),
+            });
+
+        Some(
+            widget::tooltip(
+                center_y(indicator).padding([0.0, SMALL]),
+                widget::container(text(error.clone()))
+                    .padding(SMALL)
+                    .style(widget::container::rounded_box),
+                widget::tooltip::Position::Bottom,
+            )
+            .into(),
+        )
+    }
+
+    fn module(&self, module: Module, output: Option<&str>) -> Option<Element<Message>> {
+        match module {
+            Module::Workspaces => Some(self.workspaces(output)),
+            Module::Clock => Some(self.clock()),
+            Module::Battery => self.battery(),
+            Module::Volume => self.volume(),
+            Module::Microphone => self.microphone(),
+            Module::Tray => self.tray(),
+            Module::System => self.system(),
+            Module::Input => self.input(),
+            Module::Window => self.window(),
+            Module::Mode => self.mode(),
+        }
+    }
+
+    fn section(&self, modules: &[Module], output: Option<&str>) -> Row<Message> {
+        Row::from_iter(
+            modules
+                .iter()
+                .filter_map(|&module| self.module(module, output)),
+        )
+        .spacing(SMALL)
+    }
+
+    fn view(&self, id: iced::window::Id) -> Element<Message> {
+        if self.volume_popup == Some(id) {
+            return self.volume_popup();
+        }
+        if self.tray_menu.as_ref().is_some_and(|menu| menu.id == id) {
+            return self.tray_menu();
+        }
+        if let Some((osd_id, osd)) = self.osd
+            && osd_id == id
+        {
+            return self.osd(osd);
+        }
+
+        let modules = &self.config.modules;
+        let output = self.surfaces.get(&id).map(|output| output.name.as_str());
+
+        let left = Row::new()
+            .push_maybe(self.config_error())
+            .push(self.section(&modules.left, output));
+        let left = widget::container(left).width(Length::Fill);
+        let center = self.section(&modules.center, output);
+        let right = widget::right(self.section(&modules.right, output));
+
+        row![left, center, right].width(Length::Fill).into()
     }
 
     fn update(&mut self, message: Message) -> Task<Message> {
         trace!("Update {:#?}", message);
         match message {
             Message::Tick => Task::none(),
+            Message::Config(message) => {
+                match message {
+                    ConfigMessage::Reloaded(config) => {
+                        if config.height != self.config.height
+                            || config.width != self.config.width
+                            || config.font != self.config.font
+                        {
+                            warn!("Changing the bar size or font requires a restart");
+                        }
+                        info!("Reloaded config");
+                        freedesktop::set_theme(&config.icon_theme);
+                        self.config = config;
+                        self.config_error = None;
+                        // Resolve the tray icons again with the new theme
+                        self.refresh_tray_icons();
+                    }
+                    ConfigMessage::Error(error) => {
+                        // Keep using the last good config
+                        self.config_error = Some(error);
+                    }
+                }
+                Task::none()
+            }
             Message::ClockHover(hovered) => {
                 self.clock_hovered = hovered;
                 Task::none()
@@ -281,22 +1083,73 @@ impl State {
                     SwayMessage::Initialized(sway_messenger) => {
                         self.sway_messenger = Some(sway_messenger)
                     }
+                    SwayMessage::Connected(connected) => {
+                        self.sway_connected = connected;
+                    }
                     SwayMessage::Workspaces(workspaces) => {
                         self.workspaces = workspaces;
                     }
                     SwayMessage::Input(input) => {
                         self.input = Some(input);
                     }
+                    SwayMessage::Outputs(outputs) => {
+                        let icon_size = self.tray_icon_size();
+                        let task = self.update_surfaces(outputs);
+                        if self.tray_icon_size() != icon_size {
+                            self.refresh_tray_icons();
+                        }
+                        return task;
+                    }
+                    SwayMessage::Window(window) => {
+                        self.window = window;
+                    }
+                    SwayMessage::Mode(mode) => {
+                        self.mode = (mode != "default").then_some(mode);
+                    }
+                }
+                Task::none()
+            }
+            Message::SwitchWorkspace(target) => match &mut self.sway_messenger {
+                Some(sway_messenger) => {
+                    sway_messenger.switch_workspace(target);
+                    Task::none()
+                }
+                None => {
+                    warn!("Unable to send SwitchWorkspace({target}), SwayMessenger uninitialized");
+                    Task::none()
+                }
+            },
+            Message::WorkspaceScroll(output, delta) => {
+                if !self.sway_connected {
+                    return Task::none();
+                }
+                let Some(sway_messenger) = &mut self.sway_messenger else {
+                    warn!("Unable to scroll workspaces, SwayMessenger uninitialized");
+                    return Task::none();
+                };
+
+                let config = self.config.workspaces;
+                let options = ScrollOptions {
+                    wrap: config.scroll_wrap,
+                    output: output.filter(|_| config.scroll_same_output),
+                };
+                let steps = scroll_steps(&mut self.workspace_scroll, delta);
+                for _ in 0..steps.unsigned_abs() {
+                    if steps < 0 {
+                        sway_messenger.next_workspace(options.clone());
+                    } else {
+                        sway_messenger.previous_workspace(options.clone());
+                    }
                 }
                 Task::none()
             }
-            Message::SwitchWorkspace(num) => match &mut self.sway_messenger {
+            Message::NextLayout => match &mut self.sway_messenger {
                 Some(sway_messenger) => {
-                    sway_messenger.switch_workspace(num);
+                    sway_messenger.next_layout();
                     Task::none()
                 }
                 None => {
-                    warn!("Unable to send SwitchWorkspace({num}), SwayMessenger uninitialized");
+                    warn!("Unable to send NextLayout, SwayMessenger uninitialized");
                     Task::none()
                 }
             },
@@ -308,34 +1161,124 @@ impl State {
                 }
                 Task::none()
             }
+            Message::Brightness(BrightnessMessage::Update(brightness)) => {
+                let old = self.brightness.replace(brightness);
+                // Skip the first update
+                if self.config.osd.enabled && old.is_some() {
+                    return self.show_osd(Osd::Brightness);
+                }
+                Task::none()
+            }
             Message::BatteryHover(hovered) => {
                 self.battery_hovered = hovered;
                 Task::none()
             }
-            Message::Volume(info) => {
-                if self.volume != info {
-                    self.volume = info;
+            Message::Volume(message) => {
+                match message {
+                    VolumeMessage::Initialized(volume_messenger) => {
+                        self.volume_messenger = Some(volume_messenger)
+                    }
+                    VolumeMessage::Update(device, info) => {
+                        let old = match device {
+                            Device::Sink => std::mem::replace(&mut self.volume, info),
+                            Device::Source => std::mem::replace(&mut self.microphone, info),
+                        };
+                        // Skip the first update and changes made over the bar
+                        if self.config.osd.enabled
+                            && !self.volume_hovered
+                            && old.is_some()
+                            && info.is_some()
+                        {
+                            return self.show_osd(Osd::Volume(device));
+                        }
+                    }
+                    VolumeMessage::Mixer(mixer) => {
+                        self.mixer = Some(mixer);
+                        if let Some(id) = self.volume_popup {
+                            let size = self.volume_popup_size();
+                            return Task::done(Message::SizeChange { id, size });
+                        }
+                    }
+                }
+                Task::none()
+            }
+            Message::VolumeToggleMute(device) => {
+                match &mut self.volume_messenger {
+                    Some(volume_messenger) => volume_messenger.toggle_mute(device),
+                    None => warn!("Unable to toggle mute, VolumeMessenger uninitialized"),
                 }
                 Task::none()
             }
-            Message::VolumeToggleMute => {
-                volume::toggle_mute();
+            Message::VolumeScroll(device, delta) => {
+                let Some(volume_messenger) = &mut self.volume_messenger else {
+                    warn!("Unable to change volume, VolumeMessenger uninitialized");
+                    return Task::none();
+                };
+                let steps = scroll_steps(&mut self.volume_scroll, delta);
+                if steps != 0 {
+                    let config = self.config.volume;
+                    volume_messenger.change_volume(device, steps as f32 * config.step, config.max);
+                }
                 Task::none()
             }
-            Message::VolumeScroll(delta) => {
-                if let iced::mouse::ScrollDelta::Pixels { x: _, y: delta } = delta {
-                    if delta > 1.0 {
-                        volume::decrease_volume();
-                    } else if delta < -1.0 {
-                        volume::increase_volume();
-                    }
+            Message::VolumePopup => match self.volume_popup.take() {
+                Some(id) => Task::done(Message::RemoveWindow(id)),
+                None => {
+                    let id = iced::window::Id::unique();
+                    self.volume_popup = Some(id);
+                    let settings = NewLayerShellSettings {
+                        anchor: Anchor::Top | Anchor::Right,
+                        layer: Layer::Overlay,
+                        size: Some(self.volume_popup_size()),
+                        margin: Some((SMALL as i32, SMALL as i32, 0, 0)),
+                        ..Default::default()
+                    };
+                    Task::done(Message::NewLayerShell { settings, id })
+                }
+            },
+            Message::VolumeHover(hovered) => {
+                self.volume_hovered = hovered;
+                Task::none()
+            }
+            Message::OsdHide(generation) => {
+                // Only hide after the latest change
+                if generation != self.osd_generation {
+                    return Task::none();
+                }
+                match self.osd.take() {
+                    Some((id, _)) => Task::done(Message::RemoveWindow(id)),
+                    None => Task::none(),
+                }
+            }
+            Message::SetDefaultSink(sink) => {
+                match &mut self.volume_messenger {
+                    Some(volume_messenger) => volume_messenger.set_default_sink(sink),
+                    None => warn!("Unable to set the default sink, VolumeMessenger uninitialized"),
+                }
+                Task::none()
+            }
+            Message::SetStreamVolume(index, volume) => {
+                match &mut self.volume_messenger {
+                    Some(volume_messenger) => volume_messenger.set_stream_volume(index, volume),
+                    None => warn!("Unable to set stream volume, VolumeMessenger uninitialized"),
                 }
-                Task::future(volume::volume()).map(Message::Volume)
+                Task::none()
+            }
+            Message::ToggleStreamMute(index) => {
+                match &mut self.volume_messenger {
+                    Some(volume_messenger) => volume_messenger.toggle_stream_mute(index),
+                    None => warn!("Unable to toggle stream mute, VolumeMessenger uninitialized"),
+                }
+                Task::none()
             }
             Message::Tray(message) => {
                 debug!("TrayMessage: {:#?}", message);
                 match message {
-                    TrayMessage::Initialized(tray_items) => self.tray_items = Some(tray_items),
+                    TrayMessage::Initialized(tray_messenger, tray_items) => {
+                        self.tray_messenger = Some(tray_messenger);
+                        self.tray_items = Some(tray_items);
+                        self.refresh_tray_icons();
+                    }
                     TrayMessage::Add(dest, item) => {
                         if let Some(tray_items) = &mut self.tray_items {
                             tray_items.insert(dest, item);
@@ -343,16 +1286,125 @@ impl State {
                             warn!("Unable to add tray item to uninitialized tray");
                         }
                     }
+                    TrayMessage::Update(dest, update) => {
+                        match self
+                            .tray_items
+                            .as_mut()
+                            .and_then(|tray_items| tray_items.get_mut(&dest))
+                        {
+                            Some(item) => item.update(update),
+                            None => warn!("Unable to update unknown tray item {dest}"),
+                        }
+                        // Fit the open menu to its new entries, going back to
+                        // the top if the open submenu went away
+                        let gone = self.tray_menu_entries().is_none();
+                        if let Some(menu) = self
+                            .tray_menu
+                            .as_mut()
+                            .filter(|menu| menu.destination == dest)
+                        {
+                            if gone {
+                                menu.submenus.clear();
+                            }
+                            let id = menu.id;
+                            let size = self.tray_menu_size();
+                            return Task::done(Message::SizeChange { id, size });
+                        }
+                    }
                     TrayMessage::Remove(dest) => {
                         if let Some(tray_items) = &mut self.tray_items {
                             tray_items.remove(&dest);
                         } else {
                             warn!("Unable to remove tray item from uninitialized tray");
                         }
+                        if let Some(menu) = self.tray_menu.take_if(|menu| menu.destination == dest)
+                        {
+                            return Task::done(Message::RemoveWindow(menu.id));
+                        }
                     }
                 }
                 Task::none()
             }
+            Message::TrayActivate(dest) => {
+                match &mut self.tray_messenger {
+                    Some(tray_messenger) => tray_messenger.activate(dest),
+                    None => warn!("Unable to activate tray item, TrayMessenger uninitialized"),
+                }
+                Task::none()
+            }
+            Message::TraySecondaryActivate(dest) => {
+                match &mut self.tray_messenger {
+                    Some(tray_messenger) => tray_messenger.secondary_activate(dest),
+                    None => warn!("Unable to activate tray item, TrayMessenger uninitialized"),
+                }
+                Task::none()
+            }
+            Message::TrayMenu(dest) => {
+                // Clicking the same item again closes its menu
+                if let Some(menu) = self.tray_menu.take_if(|menu| menu.destination == dest) {
+                    return Task::done(Message::RemoveWindow(menu.id));
+                }
+
+                match &mut self.tray_messenger {
+                    Some(tray_messenger) => tray_messenger.about_to_show(dest.clone()),
+                    None => warn!("Unable to prepare tray menu, TrayMessenger uninitialized"),
+                }
+
+                match &mut self.tray_menu {
+                    // Switch the open menu to this item
+                    Some(menu) => {
+                        menu.destination = dest;
+                        menu.submenus.clear();
+                        let id = menu.id;
+                        let size = self.tray_menu_size();
+                        Task::done(Message::SizeChange { id, size })
+                    }
+                    None => {
+                        let id = iced::window::Id::unique();
+                        self.tray_menu = Some(TrayMenu {
+                            id,
+                            destination: dest,
+                            submenus: Vec::new(),
+                        });
+                        let settings = NewLayerShellSettings {
+                            anchor: Anchor::Top | Anchor::Right,
+                            layer: Layer::Overlay,
+                            size: Some(self.tray_menu_size()),
+                            margin: Some((SMALL as i32, SMALL as i32, 0, 0)),
+                            ..Default::default()
+                        };
+                        Task::done(Message::NewLayerShell { settings, id })
+                    }
+                }
+            }
+            Message::TrayMenuOpen(submenu) => {
+                let Some(menu) = &mut self.tray_menu else {
+                    return Task::none();
+                };
+                match submenu {
+                    Some(submenu) => menu.submenus.push(submenu),
+                    None => {
+                        menu.submenus.pop();
+                    }
+                }
+                let id = menu.id;
+                let size = self.tray_menu_size();
+                Task::done(Message::SizeChange { id, size })
+            }
+            Message::TrayMenuClicked(item) => {
+                let Some(menu) = self.tray_menu.take() else {
+                    return Task::none();
+                };
+                match &mut self.tray_messenger {
+                    Some(tray_messenger) => tray_messenger.menu_clicked(menu.destination, item),
+                    None => warn!("Unable to click tray menu item, TrayMessenger uninitialized"),
+                }
+                Task::done(Message::RemoveWindow(menu.id))
+            }
+            Message::TrayExpand => {
+                self.tray_expanded = !self.tray_expanded;
+                Task::none()
+            }
             Message::System(message) => {
                 match message {
                     SystemMessage::Update(info) => self.system_info = Some(info),
@@ -373,16 +1425,42 @@ impl State {
     fn subscription(&self) -> Subscription<Message> {
         let tick =
             iced::time::every(iced::time::Duration::from_millis(1000)).map(|_| Message::Tick);
+        let config = Subscription::run(config::watch).map(Message::Config);
         let sway = Subscription::run(sway::sway).map(Message::Sway);
-        let battery = Subscription::run(battery::battery).map(Message::Battery);
-        let volume = iced::time::repeat(
-            volume::volume,
-            iced::time::Duration::from_millis(POLL_RATE_MS),
-        )
-        .map(Message::Volume);
-        let system = Subscription::run(system::system).map(Message::System);
-        //let tray = Subscription::run(tray::tray).map(Message::Tray);
-        Subscription::batch([tick, sway, battery, volume, system])
+
+        let modules = &self.config.modules;
+        let intervals = self.config.intervals;
+        let battery = if modules.contains(Module::Battery) {
+            Subscription::run_with(intervals.battery, battery::battery).map(Message::Battery)
+        } else {
+            Subscription::none()
+        };
+        let volume = if modules.contains(Module::Volume) || modules.contains(Module::Microphone) {
+            Subscription::run_with(intervals.volume, volume::volume).map(Message::Volume)
+        } else {
+            Subscription::none()
+        };
+        let brightness = if self.config.osd.enabled {
+            Subscription::run_with(intervals.brightness, brightness::brightness)
+                .map(Message::Brightness)
+        } else {
+            Subscription::none()
+        };
+        let system = if modules.contains(Module::System) {
+            Subscription::run_with(intervals.system, system::system).map(Message::System)
+        } else {
+            Subscription::none()
+        };
+        // Kept running across output and scale changes, which only resize
+        // the icons
+        let tray = if self.config.tray.enabled && modules.contains(Module::Tray) {
+            Subscription::run(tray::tray).map(Message::Tray)
+        } else {
+            Subscription::none()
+        };
+        Subscription::batch([
+            tick, config, sway, battery, volume, brightness, system, tray,
+        ])
     }
 
     fn style(&self, theme: &Theme) -> iced::theme::Style {
@@ -393,12 +1471,6 @@ impl State {
     }
 
     fn theme(&self) -> Theme {
-        iced::Theme::custom(
-            "Gruvbox Dark".to_string(),
-            iced::theme::Palette {
-                text: iced::color!(0xebdbb2),
-                ..iced::theme::Palette::GRUVBOX_DARK
-            },
-        )
+        self.config.theme.theme()
     }
 }
@@ -1,7 +1,9 @@
 use chrono::Local;
-use log::info;
+use log::{error, info};
 
 mod battery;
+mod brightness;
+mod config;
 mod freedesktop;
 mod iced;
 mod sway;
@@ -14,7 +16,14 @@ const POLL_RATE_MS: u64 = 100;
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     setup_logger(log::LevelFilter::Debug)?;
 
-    iced::run()?;
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{err}");
+            return Err(err.into());
+        }
+    };
+    iced::run(config)?;
 
     Ok(())
 }
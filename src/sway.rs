@@ -1,97 +1,203 @@
+use std::{path::PathBuf, time::Duration};
+
 use iced::futures::{
     SinkExt, Stream, StreamExt,
-    channel::mpsc::{self, Sender},
+    channel::mpsc::{self, Receiver, Sender},
     select,
 };
-use log::{error, warn};
-use swayipc_async::{Event, EventType};
+use log::{debug, error, warn};
+use swayipc_async::{Event, EventType, NodeType, WindowChange};
+
+use crate::freedesktop::find_icon_path;
+
+/// Size in pixels of window icons
+const WINDOW_ICON_SIZE: u16 = 24;
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+type SwayError = Box<dyn std::error::Error + Send + Sync>;
 
 pub fn sway() -> impl Stream<Item = SwayMessage> {
     iced::stream::channel(100, async move |mut output| {
         // Create the channel to communicate with the GUI
         let (sender, mut receiver) = mpsc::channel(100);
 
-        let mut connection = swayipc_async::Connection::new()
-            .await
-            .expect("Unable to connect to sway");
-
-        let workspaces = workspaces_info(&mut connection).await;
-        output
-            .send(SwayMessage::Workspaces(workspaces))
-            .await
-            .unwrap();
-
-        let mut events = swayipc_async::Connection::new()
-            .await
-            .unwrap()
-            .subscribe([EventType::Workspace, EventType::Input])
-            .await
-            .unwrap()
-            .fuse();
-
         output
             .send(SwayMessage::Initialized(SwayMessenger(sender)))
             .await
             .unwrap();
 
+        // Reconnect with exponential backoff whenever the connection drops
+        let mut backoff = MIN_BACKOFF;
         loop {
-            select! {
-                event = events.select_next_some() => {
-                    let Ok(event) = event else {
+            if let Err(err) = connect(&mut output, &mut receiver, &mut backoff).await {
+                warn!("Sway connection lost: {err}");
+            }
+            output.send(SwayMessage::Connected(false)).await.unwrap();
+
+            debug!("Reconnecting to sway in {backoff:?}");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    })
+}
+
+/// Connects to sway and handles events and tasks until the connection drops
+async fn connect(
+    output: &mut Sender<SwayMessage>,
+    receiver: &mut Receiver<SwayTask>,
+    backoff: &mut Duration,
+) -> Result<(), SwayError> {
+    // Persistent connection for queries and commands
+    let mut connection = swayipc_async::Connection::new().await?;
+    let mut events = swayipc_async::Connection::new()
+        .await?
+        .subscribe([
+            EventType::Workspace,
+            EventType::Input,
+            EventType::Output,
+            EventType::Window,
+            EventType::Mode,
+        ])
+        .await?
+        .fuse();
+
+    *backoff = MIN_BACKOFF;
+    // Drop tasks from before the reconnect
+    while let Ok(Some(_)) = receiver.try_next() {}
+    output.send(SwayMessage::Connected(true)).await.unwrap();
+
+    let outputs = outputs_info(&mut connection).await?;
+    output.send(SwayMessage::Outputs(outputs)).await.unwrap();
+
+    let workspaces = workspaces_info(&mut connection).await?;
+    output
+        .send(SwayMessage::Workspaces(workspaces))
+        .await
+        .unwrap();
+
+    let window = focused_window_info(&mut connection).await?;
+    output.send(SwayMessage::Window(window)).await.unwrap();
+
+    if let Some(input) = input_info(&mut connection).await? {
+        output.send(SwayMessage::Input(input)).await.unwrap();
+    }
+
+    let mode = connection.get_binding_state().await?;
+    output.send(SwayMessage::Mode(mode)).await.unwrap();
+
+    loop {
+        select! {
+            event = events.next() => {
+                let event = match event.ok_or("Sway event stream ended")? {
+                    Ok(event) => event,
+                    // The connection itself failed
+                    Err(swayipc_async::Error::Io(err)) => return Err(err.into()),
+                    Err(err) => {
+                        warn!("Unable to read sway event: {err}");
                         continue;
-                    };
-                    match event {
-                        Event::Workspace(_) => {
-                            let workspaces = workspaces_info(&mut connection).await;
-                            output
-                                .send(SwayMessage::Workspaces(workspaces))
-                                .await
-                                .unwrap();
-                        }
-                        Event::Input(event) => {
-                            if let Some(layout) = event.input.xkb_active_layout_name {
-                                let icon = match layout.as_str() {
-                                    "English (US)" => "indicator-keyboard-En",
-                                    "Spanish" => "indicator-keyboard-Es",
-                                    _ => {
-                                        warn!("Unknown keyboard layout {}", layout);
-                                        "indicator-keyboard"
-                                    }
-                                };
-                                let input = InputInfo { icon };
-                                output.send(SwayMessage::Input(input)).await.unwrap();
+                    }
+                };
+                match event {
+                    Event::Workspace(_) => {
+                        let workspaces = workspaces_info(&mut connection).await?;
+                        output
+                            .send(SwayMessage::Workspaces(workspaces))
+                            .await
+                            .unwrap();
+
+                        // Clear the window when focusing an empty workspace
+                        let window = focused_window_info(&mut connection).await?;
+                        output.send(SwayMessage::Window(window)).await.unwrap();
+                    }
+                    Event::Window(event) => {
+                        let window = match event.change {
+                            WindowChange::Focus | WindowChange::Title
+                                if event.container.focused =>
+                            {
+                                Some((&event.container).into())
                             }
+                            WindowChange::Close => focused_window_info(&mut connection).await?,
+                            _ => continue,
+                        };
+                        output.send(SwayMessage::Window(window)).await.unwrap();
+                    }
+                    Event::Input(event) => {
+                        if let Some(input) = InputInfo::from_input(&event.input) {
+                            output.send(SwayMessage::Input(input)).await.unwrap();
                         }
-                        _ => warn!("Unexpected event {:?}", event),
                     }
+                    Event::Output(_) => {
+                        let outputs = outputs_info(&mut connection).await?;
+                        output.send(SwayMessage::Outputs(outputs)).await.unwrap();
+
+                        // Workspaces may have moved to a different output
+                        let workspaces = workspaces_info(&mut connection).await?;
+                        output
+                            .send(SwayMessage::Workspaces(workspaces))
+                            .await
+                            .unwrap();
+                    }
+                    Event::Mode(event) => {
+                        output.send(SwayMessage::Mode(event.change)).await.unwrap();
+                    }
+                    _ => warn!("Unexpected event {:?}", event),
                 }
-                task = receiver.select_next_some() => {
-                    match task {
-                        SwayTask::SwitchWorkspace(num) => {
-                            swayipc_async::Connection::new()
-                                .await
-                                .unwrap()
-                                .run_command(format!("workspace number {num}"))
-                                .await
-                                .unwrap();
-                        }
+            }
+            task = receiver.select_next_some() => {
+                match task {
+                    SwayTask::SwitchWorkspace(target) => {
+                        run_command(&mut connection, target.command()).await?;
+                    }
+                    SwayTask::NextLayout => {
+                        run_command(&mut connection, "input type:keyboard xkb_switch_layout next")
+                            .await?;
+                    }
+                    SwayTask::NextWorkspace(options) => {
+                        scroll_workspace(&mut connection, true, &options).await?;
+                    }
+                    SwayTask::PreviousWorkspace(options) => {
+                        scroll_workspace(&mut connection, false, &options).await?;
                     }
                 }
             }
         }
-    })
+    }
+}
+
+/// Runs a sway command, only failing if the connection fails
+async fn run_command(
+    connection: &mut swayipc_async::Connection,
+    command: impl AsRef<str>,
+) -> Result<(), swayipc_async::Error> {
+    let command = command.as_ref();
+    for outcome in connection.run_command(command).await? {
+        if let Err(err) = outcome {
+            warn!("Sway command \"{command}\" failed: {err}");
+        }
+    }
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
 pub enum SwayMessage {
     Initialized(SwayMessenger),
+    /// Whether the connection to sway is up
+    Connected(bool),
     Workspaces(Vec<WorkspaceInfo>),
     Input(InputInfo),
+    Outputs(Vec<OutputInfo>),
+    Window(Option<WindowInfo>),
+    /// Name of the active binding mode
+    Mode(String),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct WorkspaceInfo {
     pub num: i32,
+    pub name: String,
+    /// Name of the output the workspace is on, `None` for placeholders
+    pub output: Option<String>,
     pub visible: bool,
     pub focused: bool,
     pub urgent: bool,
@@ -99,9 +205,19 @@ pub struct WorkspaceInfo {
 }
 
 impl WorkspaceInfo {
+    pub fn target(&self) -> WorkspaceTarget {
+        if self.name == self.num.to_string() {
+            WorkspaceTarget::Number(self.num)
+        } else {
+            WorkspaceTarget::Name(self.name.clone())
+        }
+    }
+
     fn empty(num: i32) -> Self {
         Self {
             num,
+            name: num.to_string(),
+            output: None,
             visible: false,
             focused: false,
             urgent: false,
@@ -114,6 +230,8 @@ impl From<&swayipc_async::Workspace> for WorkspaceInfo {
     fn from(workspace: &swayipc_async::Workspace) -> Self {
         Self {
             num: workspace.num,
+            name: workspace.name.clone(),
+            output: Some(workspace.output.clone()),
             visible: workspace.visible,
             focused: workspace.focused,
             urgent: workspace.urgent,
@@ -122,45 +240,350 @@ impl From<&swayipc_async::Workspace> for WorkspaceInfo {
     }
 }
 
-const WORKSPACES: usize = 10;
-async fn workspaces_info(connection: &mut swayipc_async::Connection) -> Vec<WorkspaceInfo> {
-    // Get sway workspaces
-    let sway_workspaces = connection.get_workspaces().await.unwrap();
+/// Gets the existing workspaces in sway's order
+async fn workspaces_info(
+    connection: &mut swayipc_async::Connection,
+) -> Result<Vec<WorkspaceInfo>, swayipc_async::Error> {
+    Ok(connection
+        .get_workspaces()
+        .await?
+        .iter()
+        .map(WorkspaceInfo::from)
+        .collect())
+}
 
-    // Create placeholder workspaces
-    let mut workspaces: Vec<WorkspaceInfo> = (0..WORKSPACES)
-        .map(|num| WorkspaceInfo::empty(num as i32))
+const WORKSPACES: i32 = 10;
+/// Fills in empty placeholders for workspaces 1 to 9 and 0, followed by the
+/// workspaces that don't fit a placeholder in their original order.
+pub fn with_placeholders<'a>(
+    workspaces: impl Iterator<Item = &'a WorkspaceInfo>,
+) -> Vec<WorkspaceInfo> {
+    // Workspace 0 at the end
+    let mut placeholders: Vec<WorkspaceInfo> = (1..WORKSPACES)
+        .chain([0])
+        .map(WorkspaceInfo::empty)
         .collect();
+    let mut others = Vec::new();
 
-    // Update the placeholders with the existing workspaces
-    sway_workspaces.iter().for_each(|workspace| {
-        if workspace.num >= 0 && (workspace.num as usize) < WORKSPACES {
-            workspaces[workspace.num as usize] = workspace.into();
+    for workspace in workspaces {
+        match placeholders
+            .iter_mut()
+            .find(|placeholder| !placeholder.nonempty && placeholder.num == workspace.num)
+        {
+            Some(placeholder) => *placeholder = workspace.clone(),
+            None => others.push(workspace.clone()),
         }
-    });
+    }
 
-    // Workspace 0 at the end
-    let zero = workspaces.remove(0);
-    workspaces.push(zero);
+    placeholders.extend(others);
+    placeholders
+}
+
+#[derive(Debug, Clone)]
+pub struct ScrollOptions {
+    /// Wrap around from the last workspace to the first and vice versa
+    pub wrap: bool,
+    /// Only scroll through the workspaces on this output
+    pub output: Option<String>,
+}
+
+async fn scroll_workspace(
+    connection: &mut swayipc_async::Connection,
+    next: bool,
+    options: &ScrollOptions,
+) -> Result<(), swayipc_async::Error> {
+    let workspaces = workspaces_info(connection).await?;
+    match adjacent_workspace(&workspaces, next, options) {
+        Some(target) => run_command(connection, target.command()).await,
+        None => Ok(()),
+    }
+}
+
+/// Finds the workspace after or before the current one, which is the visible
+/// workspace when limited to an output and the focused workspace otherwise.
+fn adjacent_workspace(
+    workspaces: &[WorkspaceInfo],
+    next: bool,
+    options: &ScrollOptions,
+) -> Option<WorkspaceTarget> {
+    let output = options.output.as_deref();
+    let workspaces: Vec<&WorkspaceInfo> = workspaces
+        .iter()
+        .filter(|info| output.is_none() || info.output.as_deref() == output)
+        .collect();
+
+    let current = workspaces.iter().position(|info| match output {
+        Some(_) => info.visible,
+        None => info.focused,
+    })?;
+    let last = workspaces.len() - 1;
+
+    let index = match (next, options.wrap) {
+        (true, _) if current < last => current + 1,
+        (true, true) => 0,
+        (false, _) if current > 0 => current - 1,
+        (false, true) => last,
+        _ => return None,
+    };
 
-    workspaces
+    // Switching to the current workspace could trigger back and forth
+    (index != current).then(|| workspaces[index].target())
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
+pub enum WorkspaceTarget {
+    Number(i32),
+    Name(String),
+}
+
+impl WorkspaceTarget {
+    fn command(&self) -> String {
+        match self {
+            Self::Number(num) => format!("workspace number {num}"),
+            // Backslashes first so the escapes for quotes aren't escaped again
+            Self::Name(name) => format!(
+                "workspace \"{}\"",
+                name.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for WorkspaceTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Number(num) => write!(f, "{num}"),
+            Self::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputInfo {
+    pub name: String,
+    /// Logical width of the output
+    pub width: u32,
+    /// Scale factor of the output, e.g. 2 on HiDPI outputs
+    pub scale: f32,
+}
+
+impl From<&swayipc_async::Output> for OutputInfo {
+    fn from(output: &swayipc_async::Output) -> Self {
+        Self {
+            name: output.name.clone(),
+            width: output.rect.width.max(0) as u32,
+            scale: output.scale.unwrap_or(1.0) as f32,
+        }
+    }
+}
+
+async fn outputs_info(
+    connection: &mut swayipc_async::Connection,
+) -> Result<Vec<OutputInfo>, swayipc_async::Error> {
+    Ok(connection
+        .get_outputs()
+        .await?
+        .iter()
+        .filter(|output| output.active)
+        .map(OutputInfo::from)
+        .collect())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowInfo {
+    pub title: String,
+    pub icon: Option<PathBuf>,
+}
+
+impl From<&swayipc_async::Node> for WindowInfo {
+    fn from(node: &swayipc_async::Node) -> Self {
+        // Wayland windows have an app_id, X11 windows have a class
+        let app = node.app_id.clone().or_else(|| {
+            node.window_properties
+                .as_ref()
+                .and_then(|properties| properties.class.clone())
+        });
+        let icon = app.and_then(|app| {
+            find_icon_path(&app, WINDOW_ICON_SIZE)
+                .or_else(|| find_icon_path(&app.to_lowercase(), WINDOW_ICON_SIZE))
+        });
+
+        Self {
+            title: node.name.clone().unwrap_or_default(),
+            icon,
+        }
+    }
+}
+
+/// Gets the focused window, or `None` if the focus is on a workspace
+async fn focused_window_info(
+    connection: &mut swayipc_async::Connection,
+) -> Result<Option<WindowInfo>, swayipc_async::Error> {
+    let tree = connection.get_tree().await?;
+    Ok(tree
+        .find_focused_as_ref(|node| node.focused)
+        .filter(|node| matches!(node.node_type, NodeType::Con | NodeType::FloatingCon))
+        .map(WindowInfo::from))
+}
+
+#[derive(Debug, Clone)]
 pub struct InputInfo {
-    pub icon: &'static str,
+    /// Name of the active keyboard layout, e.g. "English (US)"
+    pub layout: String,
+}
+
+impl InputInfo {
+    fn from_input(input: &swayipc_async::Input) -> Option<Self> {
+        if input.input_type != "keyboard" {
+            return None;
+        }
+        Some(Self {
+            layout: input.xkb_active_layout_name.clone()?,
+        })
+    }
+
+    /// A short code for the layout of at most 3 letters: a short variant,
+    /// e.g. "US" for "English (US)", or else the xkb code of the language,
+    /// e.g. "ES" for "Spanish" and "DE" for "German (Switzerland)"
+    pub fn short_code(&self) -> String {
+        let (language, variant) = match self.layout.split_once('(') {
+            Some((language, rest)) => (language, rest.split_once(')').map(|(variant, _)| variant)),
+            None => (self.layout.as_str(), None),
+        };
+        let language = language.trim();
+
+        let code = match variant.map(str::trim) {
+            Some(variant)
+                if (1..=3).contains(&variant.len())
+                    && variant.chars().all(|c| c.is_ascii_alphabetic()) =>
+            {
+                variant
+            }
+            _ => LANGUAGE_CODES
+                .iter()
+                .find(|(name, _)| *name == language)
+                .map_or(language, |(_, code)| code),
+        };
+        code.chars().take(3).collect::<String>().to_uppercase()
+    }
+}
+
+/// xkb layout codes of the languages in common layout names
+const LANGUAGE_CODES: &[(&str, &str)] = &[
+    ("Arabic", "ara"),
+    ("Chinese", "cn"),
+    ("Czech", "cz"),
+    ("Danish", "dk"),
+    ("Dutch", "nl"),
+    ("English", "en"),
+    ("Finnish", "fi"),
+    ("French", "fr"),
+    ("German", "de"),
+    ("Greek", "gr"),
+    ("Hebrew", "il"),
+    ("Hungarian", "hu"),
+    ("Italian", "it"),
+    ("Japanese", "jp"),
+    ("Korean", "kr"),
+    ("Norwegian", "no"),
+    ("Polish", "pl"),
+    ("Portuguese", "pt"),
+    ("Russian", "ru"),
+    ("Spanish", "es"),
+    ("Swedish", "se"),
+    ("Turkish", "tr"),
+    ("Ukrainian", "ua"),
+];
+
+/// Gets the active layout of the first keyboard
+async fn input_info(
+    connection: &mut swayipc_async::Connection,
+) -> Result<Option<InputInfo>, swayipc_async::Error> {
+    Ok(connection
+        .get_inputs()
+        .await?
+        .iter()
+        .find_map(InputInfo::from_input))
 }
 
 #[derive(Debug, Clone)]
 pub struct SwayMessenger(Sender<SwayTask>);
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 enum SwayTask {
-    SwitchWorkspace(i32),
+    SwitchWorkspace(WorkspaceTarget),
+    NextLayout,
+    NextWorkspace(ScrollOptions),
+    PreviousWorkspace(ScrollOptions),
 }
 impl SwayMessenger {
-    pub fn switch_workspace(&mut self, num: i32) {
-        if self.0.try_send(SwayTask::SwitchWorkspace(num)).is_err() {
-            error!("Unable to send SwitchWorkspace({num}) task");
+    pub fn switch_workspace(&mut self, target: WorkspaceTarget) {
+        if self
+            .0
+            .try_send(SwayTask::SwitchWorkspace(target.clone()))
+            .is_err()
+        {
+            error!("Unable to send SwitchWorkspace({target}) task");
+        }
+    }
+
+    pub fn next_layout(&mut self) {
+        if self.0.try_send(SwayTask::NextLayout).is_err() {
+            error!("Unable to send NextLayout task");
+        }
+    }
+
+    pub fn next_workspace(&mut self, options: ScrollOptions) {
+        if self.0.try_send(SwayTask::NextWorkspace(options)).is_err() {
+            error!("Unable to send NextWorkspace task");
         }
     }
+
+    pub fn previous_workspace(&mut self, options: ScrollOptions) {
+        if self
+            .0
+            .try_send(SwayTask::PreviousWorkspace(options))
+            .is_err()
+        {
+            error!("Unable to send PreviousWorkspace task");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn short_code(layout: &str) -> String {
+        InputInfo {
+            layout: layout.to_string(),
+        }
+        .short_code()
+    }
+
+    #[test]
+    fn short_variants() {
+        assert_eq!(short_code("English (US)"), "US");
+        assert_eq!(short_code("English (UK)"), "UK");
+    }
+
+    #[test]
+    fn long_variants_use_the_language() {
+        assert_eq!(short_code("German (Switzerland)"), "DE");
+        assert_eq!(short_code("English (intl., with AltGr dead keys)"), "EN");
+        assert_eq!(short_code("Spanish (Latin American)"), "ES");
+    }
+
+    #[test]
+    fn languages() {
+        assert_eq!(short_code("Spanish"), "ES");
+        assert_eq!(short_code("German"), "DE");
+        assert_eq!(short_code("Arabic"), "ARA");
+    }
+
+    #[test]
+    fn unknown_languages_are_capped() {
+        assert_eq!(short_code("Esperanto"), "ESP");
+        assert_eq!(short_code("Klingon (tlhIngan Hol)"), "KLI");
+        assert_eq!(short_code(""), "");
+    }
 }
@@ -1,8 +1,7 @@
 use iced::futures::{SinkExt, Stream};
 
-use crate::POLL_RATE_MS;
-
-pub fn system() -> impl Stream<Item = SystemMessage> {
+pub fn system(poll_rate_ms: &u64) -> impl Stream<Item = SystemMessage> {
+    let poll_rate_ms = *poll_rate_ms;
     iced::stream::channel(100, async move |mut output| {
         let refreshes = sysinfo::RefreshKind::nothing()
             .with_cpu(sysinfo::CpuRefreshKind::nothing().with_cpu_usage())
@@ -11,17 +10,16 @@ pub fn system() -> impl Stream<Item = SystemMessage> {
 
         tokio::task::spawn(async move {
             let mut interval = tokio::time::interval(
-                std::time::Duration::from_millis(POLL_RATE_MS)
+                std::time::Duration::from_millis(poll_rate_ms)
                     .max(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL),
             );
             let mut old_state = None;
             loop {
                 let new_state = (&sys).into();
                 if old_state != Some(new_state) {
-                    output
-                        .send(SystemMessage::Update(new_state))
-                        .await
-                        .expect("Unable to send update");
+                    if output.send(SystemMessage::Update(new_state)).await.is_err() {
+                        break;
+                    }
 
                     old_state = Some(new_state);
                 }
@@ -1,47 +1,131 @@
-use std::{collections::HashMap, path::PathBuf};
-
 use iced::futures::{SinkExt, Stream};
-use log::{debug, warn};
+use log::{debug, error, warn};
 use system_tray::{
-    client::{Client, Event},
-    item::StatusNotifierItem,
+    client::{ActivateRequest, Client, Event, UpdateEvent},
+    item::{IconPixmap, StatusNotifierItem, Tooltip},
+    menu::{MenuType, ToggleState, ToggleType, TrayMenu},
 };
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
 
 pub use system_tray::item::Status;
 
-use crate::freedesktop::{find_icon_path, tmp_image_from_data};
+use crate::freedesktop::{IconHandle, best_pixmap, find_icon, image_from_pixmap};
+
+mod watcher;
 
+/// Icon size until the bar sets one with [`TrayMessenger::set_icon_size`]
+const DEFAULT_ICON_SIZE: u32 = 20;
+
+/// Watches the tray items, rendering pixmaps at the icon size set by the bar
 pub fn tray() -> impl Stream<Item = TrayMessage> {
     iced::stream::channel(100, async move |mut output| {
-        let client = Client::new().await.unwrap();
+        let mut icon_size = DEFAULT_ICON_SIZE;
+        // Only started once, so the watcher outlives restarts of the
+        // subscription. The client needs it to be ready.
+        watcher::start().await;
+        let client = match Client::new().await {
+            Ok(client) => client,
+            Err(err) => {
+                warn!("Unable to start the tray client: {err}");
+                return;
+            }
+        };
         let mut tray_rx = client.subscribe();
+        let (sender, mut tasks) = unbounded_channel();
 
-        let initial_items: TrayItems = client
+        let mut initial_items: Vec<_> = client
             .items()
             .lock()
             .unwrap()
             .clone()
             .into_iter()
-            .map(|(destination, (item, _))| (destination, item.into()))
+            .map(|(destination, (item, menu))| {
+                let mut item = TrayItem::new(item, icon_size);
+                item.menu = menu.map(menu_items).unwrap_or_default();
+                (destination, item)
+            })
             .collect();
+        // Their arrival order is unknown, so at least keep it consistent
+        initial_items.sort_by(|(_, a), (_, b)| a.id.cmp(&b.id));
+        let initial_items = TrayItems(initial_items);
 
         output
-            .send(TrayMessage::Initialized(initial_items))
+            .send(TrayMessage::Initialized(
+                TrayMessenger(sender),
+                initial_items,
+            ))
             .await
             .expect("Unable to send initial items");
 
-        while let Ok(event) = tray_rx.recv().await {
+        loop {
+            let event = tokio::select! {
+                event = tray_rx.recv() => match event {
+                    Ok(event) => event,
+                    Err(_) => break,
+                },
+                Some(task) = tasks.recv() => {
+                    match task {
+                        TrayTask::SetIconSize(size) => {
+                            icon_size = size;
+                            for (destination, update) in icon_updates(&client, icon_size) {
+                                output
+                                    .send(TrayMessage::Update(destination, update))
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        task => run_task(&client, task).await,
+                    }
+                    continue;
+                }
+            };
             debug!("Event: {:#?}", event);
             match event {
                 Event::Add(destination, item) => {
-                    let item: TrayItem = (*item).into();
+                    let item = TrayItem::new(*item, icon_size);
                     output
                         .send(TrayMessage::Add(destination, item))
                         .await
                         .unwrap();
                 }
                 Event::Update(destination, update) => {
-                    debug!("Update {}: {:#?}", destination, update);
+                    let update = match update {
+                        UpdateEvent::Icon {
+                            icon_name,
+                            icon_pixmap,
+                        } => TrayUpdate::Icon(item_icon(
+                            icon_name.as_deref(),
+                            icon_pixmap.as_deref(),
+                            icon_size,
+                        )),
+                        UpdateEvent::AttentionIcon(icon_name) => TrayUpdate::AttentionIcon(
+                            resolve_icon(icon_name.as_deref(), None, icon_size),
+                        ),
+                        UpdateEvent::Title(title) => TrayUpdate::Title(title),
+                        UpdateEvent::Tooltip(tooltip) => TrayUpdate::Tooltip(
+                            tooltip.map(|tooltip| TrayTooltip::new(tooltip, icon_size)),
+                        ),
+                        UpdateEvent::Status(status) => TrayUpdate::Status(status),
+                        UpdateEvent::Menu(menu) => TrayUpdate::Menu(menu_items(menu)),
+                        // The client applies diffs to its own copy of the menu
+                        UpdateEvent::MenuDiff(_) => {
+                            let menu = client
+                                .items()
+                                .lock()
+                                .unwrap()
+                                .get(&destination)
+                                .and_then(|(_, menu)| menu.clone());
+                            TrayUpdate::Menu(menu.map(menu_items).unwrap_or_default())
+                        }
+                        update => {
+                            debug!("Unhandled update {}: {:#?}", destination, update);
+                            continue;
+                        }
+                    };
+                    output
+                        .send(TrayMessage::Update(destination, update))
+                        .await
+                        .unwrap();
                 }
                 Event::Remove(destination) => {
                     output.send(TrayMessage::Remove(destination)).await.unwrap();
@@ -51,65 +135,524 @@ pub fn tray() -> impl Stream<Item = TrayMessage> {
     })
 }
 
-pub type TrayItems = HashMap<String, TrayItem>;
+/// Resolves the icons of every item again, e.g. at a new size
+fn icon_updates(client: &Client, icon_size: u32) -> Vec<(String, TrayUpdate)> {
+    let items = client.items().lock().unwrap().clone();
+    items
+        .into_iter()
+        .flat_map(|(destination, (item, _))| {
+            let item = TrayItem::new(item, icon_size);
+            [
+                TrayUpdate::Icon(item.icon),
+                TrayUpdate::AttentionIcon(item.attention_icon),
+                TrayUpdate::Tooltip(item.tooltip),
+            ]
+            .map(|update| (destination.clone(), update))
+        })
+        .collect()
+}
+
+async fn run_task(client: &Client, task: TrayTask) {
+    let request = match task.clone() {
+        TrayTask::SetIconSize(_) => return,
+        TrayTask::Activate(address) => ActivateRequest::Default {
+            address,
+            x: 0,
+            y: 0,
+        },
+        TrayTask::SecondaryActivate(address) => ActivateRequest::Secondary {
+            address,
+            x: 0,
+            y: 0,
+        },
+        TrayTask::AboutToShow(address) => {
+            let Some(menu_path) = menu_path(client, &address) else {
+                return;
+            };
+            if let Err(err) = client.about_to_show_menuitem(address, menu_path, 0).await {
+                warn!("Unable to prepare the tray menu: {err}");
+            }
+            return;
+        }
+        TrayTask::MenuClicked(address, submenu_id) => {
+            let Some(menu_path) = menu_path(client, &address) else {
+                warn!("Unable to run {task:?}, the item has no menu");
+                return;
+            };
+            ActivateRequest::MenuItem {
+                address,
+                menu_path,
+                submenu_id,
+            }
+        }
+    };
+
+    if let Err(err) = client.activate(request).await {
+        warn!("Unable to run {task:?}: {err}");
+    }
+}
+
+fn menu_path(client: &Client, address: &str) -> Option<String> {
+    client
+        .items()
+        .lock()
+        .unwrap()
+        .get(address)
+        .and_then(|(item, _)| item.menu.clone())
+}
+
+/// Tray items by destination, in the order they arrived
+#[derive(Debug, Clone, Default)]
+pub struct TrayItems(Vec<(String, TrayItem)>);
+
+impl TrayItems {
+    /// Adds an item at the end, or replaces it in place if it already exists
+    pub fn insert(&mut self, destination: String, item: TrayItem) {
+        match self.get_mut(&destination) {
+            Some(old) => *old = item,
+            None => self.0.push((destination, item)),
+        }
+    }
+
+    pub fn remove(&mut self, destination: &str) {
+        self.0
+            .retain(|(item_destination, _)| item_destination != destination);
+    }
+
+    pub fn get(&self, destination: &str) -> Option<&TrayItem> {
+        self.iter()
+            .find(|(item_destination, _)| *item_destination == destination)
+            .map(|(_, item)| item)
+    }
+
+    pub fn get_mut(&mut self, destination: &str) -> Option<&mut TrayItem> {
+        self.0
+            .iter_mut()
+            .find(|(item_destination, _)| item_destination == destination)
+            .map(|(_, item)| item)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &TrayItem)> {
+        self.0.iter().map(|(destination, item)| (destination, item))
+    }
+
+    /// Items in display order: `pinned` ids in that order, then the rest in
+    /// arrival order, leaving out `hidden` ids
+    pub fn ordered<'a>(
+        &'a self,
+        pinned: &'a [String],
+        hidden: &'a [String],
+    ) -> impl Iterator<Item = (&'a String, &'a TrayItem)> {
+        let pinned_items = pinned
+            .iter()
+            .flat_map(move |id| self.iter().filter(move |(_, item)| item.id == *id));
+        let rest = self
+            .iter()
+            .filter(move |(_, item)| !pinned.contains(&item.id));
+
+        pinned_items
+            .chain(rest)
+            .filter(move |(_, item)| !hidden.contains(&item.id))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum TrayMessage {
-    Initialized(TrayItems),
+    Initialized(TrayMessenger, TrayItems),
     Add(String, TrayItem),
+    Update(String, TrayUpdate),
     Remove(String),
 }
 
+/// A change to a tray item, with icons already resolved
+#[derive(Debug, Clone)]
+pub enum TrayUpdate {
+    Icon(Option<IconHandle>),
+    AttentionIcon(Option<IconHandle>),
+    Title(Option<String>),
+    Tooltip(Option<TrayTooltip>),
+    Status(Status),
+    Menu(Vec<MenuItem>),
+}
+
 #[derive(Debug, Clone)]
 pub struct TrayItem {
+    pub id: String,
     pub title: String,
+    pub tooltip: Option<TrayTooltip>,
     pub status: Status,
-    pub icon: PathBuf,
+    pub icon: Option<IconHandle>,
+    /// Icon to show instead while the item needs attention
+    pub attention_icon: Option<IconHandle>,
+    /// Whether the item only supports its menu, rather than activation
+    pub item_is_menu: bool,
+    pub menu: Vec<MenuItem>,
 }
 
-impl From<StatusNotifierItem> for TrayItem {
-    fn from(item: StatusNotifierItem) -> Self {
-        let title = item.title.unwrap_or(item.id);
+impl TrayItem {
+    fn new(item: StatusNotifierItem, icon_size: u32) -> Self {
+        let title = item.title.unwrap_or_else(|| item.id.clone());
+        let tooltip = item
+            .tool_tip
+            .map(|tooltip| TrayTooltip::new(tooltip, icon_size));
         let status = item.status;
-        let icon = item
-            .icon_name
-            .and_then(|icon_name| find_icon_path(&icon_name))
-            .or_else(|| {
-                if let Some(pixmap) = item.icon_pixmap {
-                    tmp_image_from_data(&pixmap[0])
-                } else {
-                    None
-                }
-            })
-            .or_else(|| find_icon_path("notifications"))
-            .expect("Unable to find default icon");
+        let icon = item_icon(
+            item.icon_name.as_deref(),
+            item.icon_pixmap.as_deref(),
+            icon_size,
+        );
+        let attention_icon = resolve_icon(
+            item.attention_icon_name.as_deref(),
+            item.attention_icon_pixmap.as_deref(),
+            icon_size,
+        );
 
         Self {
+            id: item.id,
             title,
+            tooltip,
             status,
             icon,
+            attention_icon,
+            item_is_menu: item.item_is_menu,
+            menu: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, update: TrayUpdate) {
+        match update {
+            TrayUpdate::Icon(icon) => self.icon = icon,
+            TrayUpdate::AttentionIcon(icon) => self.attention_icon = icon,
+            TrayUpdate::Title(title) => self.title = title.unwrap_or_else(|| self.id.clone()),
+            TrayUpdate::Tooltip(tooltip) => self.tooltip = tooltip,
+            TrayUpdate::Status(status) => self.status = status,
+            TrayUpdate::Menu(menu) => self.menu = menu,
         }
     }
 }
 
-enum MenuItem {
+/// A structured tooltip, with the description split into styled spans
+#[derive(Debug, Clone)]
+pub struct TrayTooltip {
+    pub title: String,
+    pub description: Vec<MarkupSpan>,
+    pub icon: Option<IconHandle>,
+}
+
+impl TrayTooltip {
+    fn new(tooltip: Tooltip, icon_size: u32) -> Self {
+        Self {
+            title: tooltip.title,
+            description: parse_markup(&tooltip.description),
+            icon: resolve_icon(
+                Some(tooltip.icon_name.as_str()),
+                Some(tooltip.icon_data.as_slice()),
+                icon_size,
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MarkupSpan {
+    pub text: String,
+    pub bold: bool,
+    pub italic: bool,
+}
+
+/// Parses the small subset of HTML that tooltip descriptions may use, i.e.
+/// bold, italic and line breaks. Other tags are dropped.
+fn parse_markup(markup: &str) -> Vec<MarkupSpan> {
+    let mut spans = Vec::new();
+    let mut span = MarkupSpan::default();
+    let (mut bold, mut italic) = (0u32, 0u32);
+
+    let mut rest = markup;
+    while !rest.is_empty() {
+        let Some(start) = rest.find('<') else {
+            span.text.push_str(&unescape(rest));
+            break;
+        };
+        span.text.push_str(&unescape(&rest[..start]));
+        let Some(end) = rest[start..].find('>') else {
+            // Not a tag after all
+            span.text.push_str(&unescape(&rest[start..]));
+            break;
+        };
+        let tag = rest[start + 1..start + end].trim().to_lowercase();
+        rest = &rest[start + end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        match name {
+            "b" | "strong" if closing => bold = bold.saturating_sub(1),
+            "b" | "strong" => bold += 1,
+            "i" | "em" if closing => italic = italic.saturating_sub(1),
+            "i" | "em" => italic += 1,
+            "br" => {
+                span.text.push('\n');
+                continue;
+            }
+            "p" if closing => {
+                span.text.push('\n');
+                continue;
+            }
+            _ => continue,
+        }
+
+        // The style changed, so start a new span
+        let next = MarkupSpan {
+            text: String::new(),
+            bold: bold > 0,
+            italic: italic > 0,
+        };
+        let span = std::mem::replace(&mut span, next);
+        if !span.text.is_empty() {
+            spans.push(span);
+        }
+    }
+    if !span.text.is_empty() {
+        spans.push(span);
+    }
+
+    spans
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Finds the icon by name, falling back to the pixmap and then a default icon
+fn item_icon(
+    icon_name: Option<&str>,
+    icon_pixmap: Option<&[IconPixmap]>,
+    icon_size: u32,
+) -> Option<IconHandle> {
+    resolve_icon(icon_name, icon_pixmap, icon_size)
+        .or_else(|| find_icon("notifications", icon_size as u16))
+}
+
+/// Finds the icon by name, falling back to the pixmap
+fn resolve_icon(
+    icon_name: Option<&str>,
+    icon_pixmap: Option<&[IconPixmap]>,
+    icon_size: u32,
+) -> Option<IconHandle> {
+    icon_name
+        .filter(|icon_name| !icon_name.is_empty())
+        .and_then(|icon_name| find_icon(icon_name, icon_size as u16))
+        .or_else(|| {
+            let pixmap = best_pixmap(icon_pixmap?, icon_size)?;
+            image_from_pixmap(pixmap, icon_size).map(IconHandle::Image)
+        })
+}
+
+fn menu_items(menu: TrayMenu) -> Vec<MenuItem> {
+    menu.submenus
+        .into_iter()
+        .filter(|item| item.visible)
+        .map(MenuItem::from)
+        .collect()
+}
+
+/// Finds the entries of the submenu reached by following `path` of item ids
+pub fn submenu<'a>(menu: &'a [MenuItem], path: &[i32]) -> Option<&'a [MenuItem]> {
+    path.iter().try_fold(menu, |menu, &id| {
+        menu.iter().find_map(|item| match item {
+            MenuItem::Item {
+                id: item_id,
+                submenu,
+                ..
+            } if *item_id == id => Some(submenu.as_slice()),
+            _ => None,
+        })
+    })
+}
+
+#[derive(Debug, Clone)]
+pub enum MenuItem {
     Seperator,
     Item {
         id: i32,
         label: String,
+        enabled: bool,
+        toggle: Option<Toggle>,
         submenu: Vec<MenuItem>,
     },
 }
 
+/// A checkmark or radio item and whether it is on
+#[derive(Debug, Copy, Clone)]
+pub enum Toggle {
+    Checkmark(bool),
+    Radio(bool),
+}
+
 impl From<system_tray::menu::MenuItem> for MenuItem {
     fn from(item: system_tray::menu::MenuItem) -> Self {
         match item.menu_type {
-            system_tray::menu::MenuType::Separator => Self::Seperator,
-            system_tray::menu::MenuType::Standard => Self::Item {
-                id: item.id,
-                label: item.label.unwrap_or_default(),
-                submenu: item.submenu.into_iter().map(|item| item.into()).collect(),
-            },
+            MenuType::Separator => Self::Seperator,
+            MenuType::Standard => {
+                let on = matches!(item.toggle_state, ToggleState::On);
+                let toggle = match item.toggle_type {
+                    ToggleType::Checkmark => Some(Toggle::Checkmark(on)),
+                    ToggleType::Radio => Some(Toggle::Radio(on)),
+                    ToggleType::CannotBeToggled => None,
+                };
+
+                Self::Item {
+                    id: item.id,
+                    label: strip_mnemonics(&item.label.unwrap_or_default()),
+                    enabled: item.enabled,
+                    toggle,
+                    submenu: item
+                        .submenu
+                        .into_iter()
+                        .filter(|item| item.visible)
+                        .map(|item| item.into())
+                        .collect(),
+                }
+            }
         }
     }
 }
+
+/// Removes the underscores marking access keys, keeping escaped `__`
+fn strip_mnemonics(label: &str) -> String {
+    let mut stripped = String::with_capacity(label.len());
+    let mut chars = label.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '_' {
+            stripped.push(c);
+        } else if chars.next_if_eq(&'_').is_some() {
+            stripped.push('_');
+        }
+    }
+    stripped
+}
+
+#[derive(Debug, Clone)]
+enum TrayTask {
+    Activate(String),
+    SecondaryActivate(String),
+    /// Lets the item update its menu before it is shown
+    AboutToShow(String),
+    MenuClicked(String, i32),
+    SetIconSize(u32),
+}
+
+#[derive(Debug, Clone)]
+pub struct TrayMessenger(UnboundedSender<TrayTask>);
+impl TrayMessenger {
+    pub fn activate(&mut self, destination: String) {
+        self.send(TrayTask::Activate(destination));
+    }
+
+    pub fn secondary_activate(&mut self, destination: String) {
+        self.send(TrayTask::SecondaryActivate(destination));
+    }
+
+    pub fn about_to_show(&mut self, destination: String) {
+        self.send(TrayTask::AboutToShow(destination));
+    }
+
+    pub fn menu_clicked(&mut self, destination: String, id: i32) {
+        self.send(TrayTask::MenuClicked(destination, id));
+    }
+
+    /// Resolves the icons again `size` pixels tall, e.g. after the output
+    /// scale changed
+    pub fn set_icon_size(&mut self, size: u32) {
+        self.send(TrayTask::SetIconSize(size));
+    }
+
+    fn send(&mut self, task: TrayTask) {
+        if let Err(err) = self.0.send(task) {
+            error!("Unable to send {:?} task", err.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(text: &str, bold: bool, italic: bool) -> MarkupSpan {
+        MarkupSpan {
+            text: text.to_string(),
+            bold,
+            italic,
+        }
+    }
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(
+            parse_markup("Volume 50%"),
+            [span("Volume 50%", false, false)]
+        );
+        assert!(parse_markup("").is_empty());
+    }
+
+    #[test]
+    fn nested_tags() {
+        assert_eq!(
+            parse_markup("<b>bold <i>both</i></b> plain"),
+            [
+                span("bold ", true, false),
+                span("both", true, true),
+                span(" plain", false, false),
+            ]
+        );
+        assert_eq!(
+            parse_markup("<strong><b>still</b> bold</strong>"),
+            [span("still", true, false), span(" bold", true, false)]
+        );
+    }
+
+    #[test]
+    fn line_breaks() {
+        assert_eq!(
+            parse_markup("<p>one</p>two<br/>three<BR>four"),
+            [span("one\ntwo\nthree\nfour", false, false)]
+        );
+    }
+
+    #[test]
+    fn unknown_tags_are_dropped() {
+        assert_eq!(
+            parse_markup(r#"<a href="https://example.com">link</a>"#),
+            [span("link", false, false)]
+        );
+    }
+
+    #[test]
+    fn unterminated_tags() {
+        assert_eq!(parse_markup("1 <2"), [span("1 <2", false, false)]);
+        assert_eq!(parse_markup("<b>bold"), [span("bold", true, false)]);
+        assert_eq!(
+            parse_markup("<i>a</i> <b"),
+            [span("a", false, true), span(" <b", false, false)]
+        );
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(
+            parse_markup("&lt;b&gt; &amp; &quot;q&quot; &apos;a&apos;&nbsp;!"),
+            [span("<b> & \"q\" 'a' !", false, false)]
+        );
+        // Decoded once, not again after decoding &amp;
+        assert_eq!(unescape("&amp;lt;"), "&lt;");
+        assert_eq!(unescape("a &unknown; b"), "a &unknown; b");
+    }
+}
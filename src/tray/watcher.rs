@@ -0,0 +1,435 @@
+use iced::futures::{Stream, StreamExt};
+use log::{debug, info, warn};
+use tokio::sync::OnceCell;
+use zbus::{
+    fdo,
+    fdo::{NameLost, NameOwnerChanged, RequestNameFlags, RequestNameReply},
+    interface,
+    message::Header,
+    object_server::{InterfaceRef, SignalEmitter},
+};
+
+const NAME: &str = "org.kde.StatusNotifierWatcher";
+const PATH: &str = "/StatusNotifierWatcher";
+
+/// The watcher hosted by this process, if any, which is kept until it exits
+static WATCHER: OnceCell<Option<WatcherHandle>> = OnceCell::const_new();
+
+/// Hosts the StatusNotifierWatcher until dropped. The one in [`WATCHER`] never
+/// is, but the tests stop theirs before their bus goes away.
+struct WatcherHandle(tokio::task::JoinHandle<()>);
+
+impl Drop for WatcherHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Hosts the StatusNotifierWatcher if no other process does, so that items
+/// can be found on setups without one. Only the first call tries, and the
+/// watcher is then kept for the rest of the process. Returns once the
+/// watcher is ready or another one is found.
+pub async fn start() {
+    WATCHER
+        .get_or_init(|| async {
+            let builder = match zbus::connection::Builder::session() {
+                Ok(builder) => builder,
+                Err(err) => {
+                    warn!("Unable to connect to the session bus: {err}");
+                    return None;
+                }
+            };
+            match serve(builder).await {
+                Ok(Some(handle)) => Some(handle),
+                Ok(None) => {
+                    debug!("Using the existing StatusNotifierWatcher");
+                    None
+                }
+                Err(err) => {
+                    warn!("Unable to host the StatusNotifierWatcher: {err}");
+                    None
+                }
+            }
+        })
+        .await;
+}
+
+async fn serve(builder: zbus::connection::Builder<'_>) -> zbus::Result<Option<WatcherHandle>> {
+    let connection = builder.serve_at(PATH, Watcher::default())?.build().await?;
+    // Subscribe before claiming the name so no departures are missed
+    let dbus = fdo::DBusProxy::new(&connection).await?;
+    let owner_changes = dbus.receive_name_owner_changed().await?;
+    let names_lost = dbus.receive_name_lost().await?;
+
+    // Let a dedicated watcher replace us, and don't wait in line for one
+    // that is already running
+    let flags = RequestNameFlags::AllowReplacement | RequestNameFlags::DoNotQueue;
+    match connection.request_name_with_flags(NAME, flags).await {
+        Ok(RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner) => {}
+        Ok(_) | Err(zbus::Error::NameTaken) => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    info!("Hosting the StatusNotifierWatcher");
+
+    let watcher = connection
+        .object_server()
+        .interface::<_, Watcher>(PATH)
+        .await?;
+    let handle = tokio::spawn(async move {
+        if let Err(err) = run(&watcher, owner_changes, names_lost).await {
+            warn!("StatusNotifierWatcher stopped: {err}");
+        }
+        // Give up the name and object so another watcher can take over
+        let _ = connection.object_server().remove::<Watcher, _>(PATH).await;
+        let _ = connection.release_name(NAME).await;
+    });
+
+    Ok(Some(WatcherHandle(handle)))
+}
+
+/// Unregisters items and hosts as they leave the bus, until the name is
+/// taken over by another watcher. Only returns errors of the connection.
+async fn run(
+    watcher: &InterfaceRef<Watcher>,
+    mut owner_changes: impl Stream<Item = NameOwnerChanged> + Unpin,
+    mut names_lost: impl Stream<Item = NameLost> + Unpin,
+) -> zbus::Result<()> {
+    loop {
+        tokio::select! {
+            Some(change) = owner_changes.next() => {
+                let args = match change.args() {
+                    Ok(args) => args,
+                    Err(err) => {
+                        warn!("Unable to read NameOwnerChanged: {err}");
+                        continue;
+                    }
+                };
+                if args.new_owner().is_some() {
+                    continue;
+                }
+                let emitter = watcher.signal_emitter();
+                watcher.get_mut().await.remove(args.name().as_str(), emitter).await?;
+            }
+            Some(lost) = names_lost.next() => {
+                let args = match lost.args() {
+                    Ok(args) => args,
+                    Err(err) => {
+                        warn!("Unable to read NameLost: {err}");
+                        continue;
+                    }
+                };
+                if args.name() == NAME {
+                    info!("Handing the StatusNotifierWatcher over to another process");
+                    return Ok(());
+                }
+            }
+            else => return Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Watcher {
+    /// Items as their bus name, followed by their object path if it was
+    /// registered with one
+    items: Vec<String>,
+    /// Bus names of the hosts
+    hosts: Vec<String>,
+}
+
+impl Watcher {
+    /// Removes the items and hosts of a bus name that went away
+    async fn remove(&mut self, name: &str, emitter: &SignalEmitter<'_>) -> zbus::Result<()> {
+        let (removed, items) = std::mem::take(&mut self.items)
+            .into_iter()
+            .partition::<Vec<_>, _>(|item| bus_name(item) == name);
+        self.items = items;
+        for item in &removed {
+            debug!("Unregistered tray item {item}");
+            Self::status_notifier_item_unregistered(emitter, item).await?;
+        }
+        if !removed.is_empty() {
+            self.registered_status_notifier_items_changed(emitter)
+                .await?;
+        }
+
+        let hosts = self.hosts.len();
+        self.hosts.retain(|host| host != name);
+        if self.hosts.len() != hosts {
+            debug!("Unregistered tray host {name}");
+            self.is_status_notifier_host_registered_changed(emitter)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[interface(name = "org.kde.StatusNotifierWatcher")]
+impl Watcher {
+    async fn register_status_notifier_item(
+        &mut self,
+        service: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> fdo::Result<()> {
+        let sender = header
+            .sender()
+            .ok_or_else(|| fdo::Error::InvalidArgs(String::from("Missing sender")))?;
+        // Some items register their object path rather than a bus name
+        let item = if service.starts_with('/') {
+            format!("{sender}{service}")
+        } else {
+            service.to_string()
+        };
+
+        if !self.items.contains(&item) {
+            debug!("Registered tray item {item}");
+            self.items.push(item.clone());
+            Self::status_notifier_item_registered(&emitter, &item).await?;
+            self.registered_status_notifier_items_changed(&emitter)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn register_status_notifier_host(
+        &mut self,
+        service: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> fdo::Result<()> {
+        // Hosts are unregistered when their connection goes away
+        let host = match header.sender() {
+            Some(sender) => sender.to_string(),
+            None => service.to_string(),
+        };
+
+        if !self.hosts.contains(&host) {
+            debug!("Registered tray host {host}");
+            let first = self.hosts.is_empty();
+            self.hosts.push(host);
+            Self::status_notifier_host_registered(&emitter).await?;
+            if first {
+                self.is_status_notifier_host_registered_changed(&emitter)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[zbus(property)]
+    fn registered_status_notifier_items(&self) -> Vec<String> {
+        self.items.clone()
+    }
+
+    #[zbus(property)]
+    fn is_status_notifier_host_registered(&self) -> bool {
+        !self.hosts.is_empty()
+    }
+
+    #[zbus(property)]
+    fn protocol_version(&self) -> i32 {
+        0
+    }
+
+    #[zbus(signal)]
+    async fn status_notifier_item_registered(
+        emitter: &SignalEmitter<'_>,
+        service: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn status_notifier_item_unregistered(
+        emitter: &SignalEmitter<'_>,
+        service: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn status_notifier_host_registered(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+}
+
+/// The bus name part of a registered item
+fn bus_name(item: &str) -> &str {
+    item.split_once('/').map_or(item, |(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        time::Duration,
+    };
+
+    use zbus::{Connection, Proxy, proxy::CacheProperties};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A private session bus, killed when dropped
+    struct Bus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Bus {
+        fn start() -> Self {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("Unable to start dbus-daemon");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .expect("Unable to read the bus address");
+
+            Self {
+                daemon,
+                address: address.trim().to_string(),
+            }
+        }
+
+        fn builder(&self) -> zbus::connection::Builder<'_> {
+            zbus::connection::Builder::address(self.address.as_str()).unwrap()
+        }
+
+        async fn connect(&self) -> Connection {
+            self.builder().build().await.unwrap()
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    async fn watcher_proxy(connection: &Connection) -> Proxy<'static> {
+        zbus::proxy::Builder::new(connection)
+            .destination(NAME)
+            .unwrap()
+            .path(PATH)
+            .unwrap()
+            .interface(NAME)
+            .unwrap()
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    async fn registered_items(proxy: &Proxy<'_>) -> Vec<String> {
+        proxy
+            .get_property("RegisteredStatusNotifierItems")
+            .await
+            .unwrap()
+    }
+
+    async fn name_owner(connection: &Connection) -> String {
+        let dbus = fdo::DBusProxy::new(connection).await.unwrap();
+        dbus.get_name_owner(NAME.try_into().unwrap())
+            .await
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn claims_free_name() {
+        let bus = Bus::start();
+        let _watcher = serve(bus.builder())
+            .await
+            .unwrap()
+            .expect("The name should be free");
+
+        let connection = bus.connect().await;
+        let dbus = fdo::DBusProxy::new(&connection).await.unwrap();
+        assert!(dbus.name_has_owner(NAME.try_into().unwrap()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn registers_items() {
+        let bus = Bus::start();
+        let _watcher = serve(bus.builder()).await.unwrap().unwrap();
+
+        let host = bus.connect().await;
+        let proxy = watcher_proxy(&host).await;
+        let mut registered = proxy
+            .receive_signal("StatusNotifierItemRegistered")
+            .await
+            .unwrap();
+
+        let item = bus.connect().await;
+        watcher_proxy(&item)
+            .await
+            .call_method("RegisterStatusNotifierItem", &("/StatusNotifierItem",))
+            .await
+            .unwrap();
+        let expected = format!("{}/StatusNotifierItem", item.unique_name().unwrap());
+
+        let signal = tokio::time::timeout(TIMEOUT, registered.next())
+            .await
+            .expect("No StatusNotifierItemRegistered signal")
+            .unwrap();
+        let service: String = signal.body().deserialize().unwrap();
+        assert_eq!(service, expected);
+        assert_eq!(registered_items(&proxy).await, vec![expected]);
+    }
+
+    #[tokio::test]
+    async fn removes_items_when_they_leave() {
+        let bus = Bus::start();
+        let _watcher = serve(bus.builder()).await.unwrap().unwrap();
+
+        let host = bus.connect().await;
+        let proxy = watcher_proxy(&host).await;
+        let mut unregistered = proxy
+            .receive_signal("StatusNotifierItemUnregistered")
+            .await
+            .unwrap();
+
+        let item = bus.connect().await;
+        watcher_proxy(&item)
+            .await
+            .call_method("RegisterStatusNotifierItem", &("/StatusNotifierItem",))
+            .await
+            .unwrap();
+        assert_eq!(registered_items(&proxy).await.len(), 1);
+
+        let expected = format!("{}/StatusNotifierItem", item.unique_name().unwrap());
+        item.close().await.unwrap();
+
+        let signal = tokio::time::timeout(TIMEOUT, unregistered.next())
+            .await
+            .expect("No StatusNotifierItemUnregistered signal")
+            .unwrap();
+        let service: String = signal.body().deserialize().unwrap();
+        assert_eq!(service, expected);
+        assert!(registered_items(&proxy).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn steps_aside_for_replacement() {
+        let bus = Bus::start();
+        let mut watcher = serve(bus.builder()).await.unwrap().unwrap();
+
+        let replacement = bus.connect().await;
+        let reply = replacement
+            .request_name_with_flags(NAME, RequestNameFlags::ReplaceExisting.into())
+            .await
+            .unwrap();
+        assert_eq!(reply, RequestNameReply::PrimaryOwner);
+
+        tokio::time::timeout(TIMEOUT, &mut watcher.0)
+            .await
+            .expect("The watcher kept running")
+            .unwrap();
+        assert_eq!(
+            name_owner(&replacement).await,
+            replacement.unique_name().unwrap().to_string()
+        );
+    }
+}
@@ -1,82 +1,206 @@
-use std::process::Command;
+use std::{collections::HashMap, time::Duration};
 
-use log::warn;
+use iced::futures::{SinkExt, Stream};
+use log::{error, warn};
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
 
-pub async fn volume() -> Option<VolumeInfo> {
-    get_info().ok()
+mod pulse;
+mod wpctl;
+
+/// Watches the default sink and source, using PulseAudio (or pipewire-pulse)
+/// when available and polling wpctl otherwise.
+pub fn volume(poll_rate_ms: &u64) -> impl Stream<Item = VolumeMessage> {
+    let poll_rate = Duration::from_millis(*poll_rate_ms);
+    iced::stream::channel(100, async move |mut output| {
+        let (updates, mut receiver) = unbounded_channel();
+        let (sender, tasks) = unbounded_channel();
+
+        match pulse::spawn(updates.clone(), tasks).await {
+            Ok(()) => {}
+            Err((err, tasks)) => {
+                warn!("Unable to use PulseAudio, falling back to wpctl: {err}");
+                tokio::task::spawn(wpctl::run(updates, tasks, poll_rate));
+            }
+        }
+
+        output
+            .send(VolumeMessage::Initialized(VolumeMessenger(sender)))
+            .await
+            .expect("Unable to send volume messenger");
+
+        let mut old_infos = HashMap::new();
+        while let Some(update) = receiver.recv().await {
+            let message = match update {
+                BackendUpdate::Device(device, info) => {
+                    if old_infos.insert(device, info) == Some(info) {
+                        continue;
+                    }
+                    VolumeMessage::Update(device, info)
+                }
+                BackendUpdate::Mixer(mixer) => VolumeMessage::Mixer(mixer),
+            };
+            // The subscription was dropped, e.g. after a config reload
+            if output.send(message).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+#[derive(Debug, Clone)]
+pub enum VolumeMessage {
+    Initialized(VolumeMessenger),
+    Update(Device, Option<VolumeInfo>),
+    /// Only sent by backends that can list devices and streams
+    Mixer(Mixer),
+}
+
+/// What a backend reports
+#[derive(Debug)]
+enum BackendUpdate {
+    Device(Device, Option<VolumeInfo>),
+    Mixer(Mixer),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Mixer {
+    pub sinks: Vec<SinkEntry>,
+    pub streams: Vec<StreamEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SinkEntry {
+    pub name: String,
+    pub description: String,
+    pub default: bool,
+}
+
+/// An application playing audio
+#[derive(Debug, Clone)]
+pub struct StreamEntry {
+    pub index: u32,
+    pub name: String,
+    pub volume: u32,
+    pub muted: bool,
+}
+
+/// The default output (sink) or input (source)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Device {
+    Sink,
+    Source,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct VolumeInfo {
     pub volume: u32,
+    pub muted: bool,
     pub icon: &'static str,
+    /// Nerd Font glyph for the same state as the icon
+    pub glyph: char,
 }
 
-fn get_info() -> Result<VolumeInfo, Box<dyn std::error::Error>> {
-    let stdout = String::try_from(
-        Command::new("wpctl")
-            .arg("get-volume")
-            .arg("@DEFAULT_AUDIO_SINK@")
-            .output()?
-            .stdout,
-    )?;
+impl VolumeInfo {
+    fn new(device: Device, volume: u32, muted: bool) -> Self {
+        Self {
+            volume,
+            muted,
+            icon: icon(device, volume, muted),
+            glyph: glyph(device, volume, muted),
+        }
+    }
+}
 
-    let volume = stdout
-        .matches(char::is_numeric)
-        .collect::<String>()
-        .parse::<u32>()?;
+#[derive(Debug, Clone)]
+enum VolumeTask {
+    ToggleMute(Device),
+    /// Changes the volume by a percentage, without raising it above a
+    /// maximum percentage
+    ChangeVolume(Device, f32, f32),
+    SetDefaultSink(String),
+    /// Sets the volume of a stream as a percentage
+    SetStreamVolume(u32, u32),
+    ToggleStreamMute(u32),
+}
 
-    let muted = stdout.contains("MUTED");
+#[derive(Debug, Clone)]
+pub struct VolumeMessenger(UnboundedSender<VolumeTask>);
+impl VolumeMessenger {
+    pub fn toggle_mute(&mut self, device: Device) {
+        self.send(VolumeTask::ToggleMute(device));
+    }
 
-    let icon = icon(volume, muted);
+    pub fn change_volume(&mut self, device: Device, delta: f32, max: f32) {
+        self.send(VolumeTask::ChangeVolume(device, delta, max));
+    }
 
-    Ok(VolumeInfo { volume, icon })
-}
+    pub fn set_default_sink(&mut self, sink: String) {
+        self.send(VolumeTask::SetDefaultSink(sink));
+    }
 
-pub fn toggle_mute() {
-    if Command::new("wpctl")
-        .arg("set-mute")
-        .arg("@DEFAULT_AUDIO_SINK@")
-        .arg("toggle")
-        .output()
-        .is_err()
-    {
-        warn!("Unable to toggle mute");
+    pub fn set_stream_volume(&mut self, index: u32, volume: u32) {
+        self.send(VolumeTask::SetStreamVolume(index, volume));
+    }
+
+    pub fn toggle_stream_mute(&mut self, index: u32) {
+        self.send(VolumeTask::ToggleStreamMute(index));
     }
-}
 
-pub fn increase_volume() {
-    let output = Command::new("wpctl")
-        .arg("set-volume")
-        .arg("--limit")
-        .arg("1.0")
-        .arg("@DEFAULT_AUDIO_SINK@")
-        .arg("1%+")
-        .output();
-    if !output.is_ok_and(|output| output.status.success()) {
-        warn!("Unable to increase volume");
+    fn send(&mut self, task: VolumeTask) {
+        if let Err(err) = self.0.send(task) {
+            error!("Unable to send {:?} task", err.0);
+        }
     }
 }
 
-pub fn decrease_volume() {
-    let output = Command::new("wpctl")
-        .arg("set-volume")
-        .arg("@DEFAULT_AUDIO_SINK@")
-        .arg("1%-")
-        .output();
-    if !output.is_ok_and(|output| output.status.success()) {
-        warn!("Unable to decrease volume");
+fn icon(device: Device, volume: u32, muted: bool) -> &'static str {
+    match device {
+        Device::Sink => {
+            if muted {
+                "audio-volume-muted"
+            } else if volume <= 33 {
+                "audio-volume-low"
+            } else if volume <= 66 {
+                "audio-volume-medium"
+            } else {
+                "audio-volume-high"
+            }
+        }
+        Device::Source => {
+            if muted {
+                "microphone-sensitivity-muted"
+            } else {
+                "microphone-sensitivity-high"
+            }
+        }
     }
 }
 
-fn icon(volume: u32, muted: bool) -> &'static str {
-    if muted {
-        "audio-volume-muted"
-    } else if volume <= 33 {
-        "audio-volume-low"
-    } else if volume <= 66 {
-        "audio-volume-medium"
-    } else {
-        "audio-volume-high"
+fn glyph(device: Device, volume: u32, muted: bool) -> char {
+    match device {
+        Device::Sink => {
+            if muted {
+                // nf-md-volume_off
+                '\u{f0581}'
+            } else if volume <= 33 {
+                // nf-md-volume_low
+                '\u{f057f}'
+            } else if volume <= 66 {
+                // nf-md-volume_medium
+                '\u{f0580}'
+            } else {
+                // nf-md-volume_high
+                '\u{f057e}'
+            }
+        }
+        Device::Source => {
+            if muted {
+                // nf-md-microphone_off
+                '\u{f036d}'
+            } else {
+                // nf-md-microphone
+                '\u{f036c}'
+            }
+        }
     }
 }
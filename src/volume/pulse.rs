@@ -0,0 +1,403 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use libpulse_binding::{
+    callbacks::ListResult,
+    context::{
+        Context, FlagSet, State,
+        introspect::Introspector,
+        subscribe::{Facility, InterestMaskSet},
+    },
+    mainloop::threaded::Mainloop,
+    volume::{ChannelVolumes, Volume},
+};
+use log::{debug, warn};
+use tokio::sync::{
+    mpsc::{UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+use super::{BackendUpdate, Device, Mixer, SinkEntry, StreamEntry, VolumeInfo, VolumeTask};
+
+type Tasks = UnboundedReceiver<VolumeTask>;
+
+/// Connects to the PulseAudio server on its own thread, which then sends
+/// changes through `updates` and runs `tasks`. On failure the tasks are
+/// handed back so another backend can run them.
+pub async fn spawn(
+    updates: UnboundedSender<BackendUpdate>,
+    tasks: Tasks,
+) -> Result<(), (String, Tasks)> {
+    let (ready, connected) = oneshot::channel();
+
+    // The mainloop and context aren't Send, so they live on this thread
+    std::thread::Builder::new()
+        .name(String::from("pulse"))
+        .spawn(move || match Connection::new(updates) {
+            Ok(connection) => {
+                let _ = ready.send(Ok(()));
+                connection.run(tasks);
+            }
+            Err(err) => {
+                let _ = ready.send(Err((err, tasks)));
+            }
+        })
+        .expect("Unable to spawn the pulse thread");
+
+    connected
+        .await
+        .expect("The pulse thread stopped unexpectedly")
+}
+
+fn name(device: Device) -> &'static str {
+    match device {
+        Device::Sink => "@DEFAULT_SINK@",
+        Device::Source => "@DEFAULT_SOURCE@",
+    }
+}
+
+fn from_percent(percent: f32) -> Volume {
+    Volume((Volume::NORMAL.0 as f64 * percent as f64 / 100.0).round() as u32)
+}
+
+fn percent(volumes: &ChannelVolumes) -> u32 {
+    (volumes.avg().0 as f64 * 100.0 / Volume::NORMAL.0 as f64).round() as u32
+}
+
+#[derive(Debug, Copy, Clone)]
+struct DeviceState {
+    volumes: ChannelVolumes,
+    muted: bool,
+}
+
+#[derive(Debug, Default)]
+struct MixerState {
+    default_sink: Option<String>,
+    sinks: Vec<SinkEntry>,
+    streams: Vec<(StreamEntry, ChannelVolumes)>,
+}
+
+impl MixerState {
+    fn snapshot(&self) -> Mixer {
+        Mixer {
+            sinks: self
+                .sinks
+                .iter()
+                .map(|sink| SinkEntry {
+                    default: self.default_sink.as_ref() == Some(&sink.name),
+                    ..sink.clone()
+                })
+                .collect(),
+            streams: self
+                .streams
+                .iter()
+                .map(|(stream, _)| stream.clone())
+                .collect(),
+        }
+    }
+}
+
+/// State shared between the subscription callbacks and the task loop
+#[derive(Clone)]
+struct Shared {
+    updates: UnboundedSender<BackendUpdate>,
+    devices: Arc<Mutex<HashMap<Device, DeviceState>>>,
+    mixer: Arc<Mutex<MixerState>>,
+}
+
+impl Shared {
+    /// Records the state of a device and sends its info, or clears it if
+    /// `state` is `None`
+    fn update_device(&self, device: Device, state: Option<DeviceState>) {
+        let info = state.map(|state| VolumeInfo::new(device, percent(&state.volumes), state.muted));
+
+        let mut devices = self.devices.lock().unwrap();
+        match state {
+            Some(state) => devices.insert(device, state),
+            None => devices.remove(&device),
+        };
+        let _ = self.updates.send(BackendUpdate::Device(device, info));
+    }
+
+    fn update_mixer(&self, update: impl FnOnce(&mut MixerState)) {
+        let mut mixer = self.mixer.lock().unwrap();
+        update(&mut mixer);
+        let _ = self.updates.send(BackendUpdate::Mixer(mixer.snapshot()));
+    }
+}
+
+struct Connection {
+    mainloop: Mainloop,
+    context: Context,
+    introspector: Introspector,
+    shared: Shared,
+}
+
+impl Connection {
+    fn new(updates: UnboundedSender<BackendUpdate>) -> Result<Self, String> {
+        let mut mainloop = Mainloop::new().ok_or("Unable to create the mainloop")?;
+        let mut context = Context::new(&mainloop, env!("CARGO_PKG_NAME"))
+            .ok_or("Unable to create the context")?;
+
+        context
+            .connect(None, FlagSet::NOFLAGS, None)
+            .map_err(|err| err.to_string())?;
+        mainloop.start().map_err(|err| err.to_string())?;
+
+        // Wait for the connection to be established
+        loop {
+            mainloop.lock();
+            let state = context.get_state();
+            mainloop.unlock();
+
+            match state {
+                State::Ready => break,
+                State::Failed | State::Terminated => {
+                    mainloop.stop();
+                    return Err(String::from("Unable to connect to the server"));
+                }
+                _ => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        debug!("Connected to the PulseAudio server");
+
+        let shared = Shared {
+            updates,
+            devices: Arc::default(),
+            mixer: Arc::default(),
+        };
+
+        mainloop.lock();
+        let introspector = context.introspect();
+        {
+            // Query whatever changed
+            let introspector = context.introspect();
+            let shared = shared.clone();
+            context.set_subscribe_callback(Some(Box::new(move |facility, _, _| match facility {
+                Some(Facility::Sink) => {
+                    query_sink(&introspector, &shared);
+                    query_sinks(&introspector, &shared);
+                }
+                Some(Facility::Source) => query_source(&introspector, &shared),
+                Some(Facility::SinkInput) => query_streams(&introspector, &shared),
+                Some(Facility::Server) => {
+                    query_sink(&introspector, &shared);
+                    query_source(&introspector, &shared);
+                    query_server(&introspector, &shared);
+                }
+                _ => {}
+            })));
+        }
+        context.subscribe(
+            InterestMaskSet::SINK
+                | InterestMaskSet::SOURCE
+                | InterestMaskSet::SINK_INPUT
+                | InterestMaskSet::SERVER,
+            |success| {
+                if !success {
+                    warn!("Unable to subscribe to changes");
+                }
+            },
+        );
+        query_sink(&introspector, &shared);
+        query_source(&introspector, &shared);
+        query_server(&introspector, &shared);
+        query_sinks(&introspector, &shared);
+        query_streams(&introspector, &shared);
+        mainloop.unlock();
+
+        Ok(Self {
+            mainloop,
+            context,
+            introspector,
+            shared,
+        })
+    }
+
+    /// Runs tasks until the sender is dropped
+    fn run(mut self, mut tasks: Tasks) {
+        while let Some(task) = tasks.blocking_recv() {
+            self.mainloop.lock();
+            self.run_task(task);
+            self.mainloop.unlock();
+        }
+
+        self.mainloop.lock();
+        self.context.disconnect();
+        self.mainloop.unlock();
+        self.mainloop.stop();
+    }
+
+    /// Runs a task, the mainloop must be locked
+    fn run_task(&mut self, task: VolumeTask) {
+        match task {
+            VolumeTask::ToggleMute(device) | VolumeTask::ChangeVolume(device, _, _) => {
+                let Some(state) = self.shared.devices.lock().unwrap().get(&device).copied() else {
+                    warn!("Unable to run {task:?}, the device is unknown");
+                    return;
+                };
+
+                let mut volumes = state.volumes;
+                match (&task, device) {
+                    (VolumeTask::ToggleMute(_), Device::Sink) => {
+                        self.introspector
+                            .set_sink_mute_by_name(name(device), !state.muted, None);
+                        return;
+                    }
+                    (VolumeTask::ToggleMute(_), Device::Source) => {
+                        self.introspector
+                            .set_source_mute_by_name(name(device), !state.muted, None);
+                        return;
+                    }
+                    (VolumeTask::ChangeVolume(_, delta, max), _) => {
+                        let step = from_percent(delta.abs());
+                        if *delta > 0.0 {
+                            volumes.inc_clamp(step, from_percent(*max));
+                        } else {
+                            volumes.decrease(step);
+                        }
+                    }
+                    _ => {}
+                }
+
+                match device {
+                    Device::Sink => {
+                        self.introspector
+                            .set_sink_volume_by_name(name(device), &volumes, None);
+                    }
+                    Device::Source => {
+                        self.introspector
+                            .set_source_volume_by_name(name(device), &volumes, None);
+                    }
+                }
+            }
+            VolumeTask::SetDefaultSink(sink) => {
+                self.context.set_default_sink(&sink, |success| {
+                    if !success {
+                        warn!("Unable to set the default sink");
+                    }
+                });
+            }
+            VolumeTask::SetStreamVolume(index, volume) => {
+                let mixer = self.shared.mixer.lock().unwrap();
+                let Some((_, volumes)) = mixer
+                    .streams
+                    .iter()
+                    .find(|(stream, _)| stream.index == index)
+                else {
+                    warn!("Unable to find stream {index}");
+                    return;
+                };
+
+                let mut volumes = *volumes;
+                volumes.set(volumes.len(), from_percent(volume as f32));
+                self.introspector
+                    .set_sink_input_volume(index, &volumes, None);
+            }
+            VolumeTask::ToggleStreamMute(index) => {
+                let mixer = self.shared.mixer.lock().unwrap();
+                let Some((stream, _)) = mixer
+                    .streams
+                    .iter()
+                    .find(|(stream, _)| stream.index == index)
+                else {
+                    warn!("Unable to find stream {index}");
+                    return;
+                };
+
+                self.introspector
+                    .set_sink_input_mute(index, !stream.muted, None);
+            }
+        }
+    }
+}
+
+fn query_sink(introspector: &Introspector, shared: &Shared) {
+    let shared = shared.clone();
+    introspector.get_sink_info_by_name(name(Device::Sink), move |result| {
+        let state = match result {
+            ListResult::Item(info) => Some(DeviceState {
+                volumes: info.volume,
+                muted: info.mute,
+            }),
+            ListResult::Error => None,
+            ListResult::End => return,
+        };
+        shared.update_device(Device::Sink, state);
+    });
+}
+
+fn query_source(introspector: &Introspector, shared: &Shared) {
+    let shared = shared.clone();
+    introspector.get_source_info_by_name(name(Device::Source), move |result| {
+        let state = match result {
+            // Without a capture device the default source is a sink monitor
+            ListResult::Item(info) if info.monitor_of_sink.is_some() => None,
+            ListResult::Item(info) => Some(DeviceState {
+                volumes: info.volume,
+                muted: info.mute,
+            }),
+            ListResult::Error => None,
+            ListResult::End => return,
+        };
+        shared.update_device(Device::Source, state);
+    });
+}
+
+fn query_server(introspector: &Introspector, shared: &Shared) {
+    let shared = shared.clone();
+    introspector.get_server_info(move |info| {
+        let default_sink = info.default_sink_name.as_ref().map(|name| name.to_string());
+        shared.update_mixer(|mixer| mixer.default_sink = default_sink);
+    });
+}
+
+fn query_sinks(introspector: &Introspector, shared: &Shared) {
+    let shared = shared.clone();
+    let mut sinks = Vec::new();
+    introspector.get_sink_info_list(move |result| match result {
+        ListResult::Item(info) => {
+            let Some(name) = &info.name else {
+                return;
+            };
+            sinks.push(SinkEntry {
+                name: name.to_string(),
+                description: info.description.as_ref().unwrap_or(name).to_string(),
+                default: false,
+            });
+        }
+        ListResult::End => {
+            let sinks = std::mem::take(&mut sinks);
+            shared.update_mixer(|mixer| mixer.sinks = sinks);
+        }
+        ListResult::Error => warn!("Unable to list sinks"),
+    });
+}
+
+fn query_streams(introspector: &Introspector, shared: &Shared) {
+    let shared = shared.clone();
+    let mut streams = Vec::new();
+    introspector.get_sink_input_info_list(move |result| match result {
+        ListResult::Item(info) => {
+            let name = info
+                .proplist
+                .get_str("application.name")
+                .or_else(|| info.name.as_ref().map(|name| name.to_string()))
+                .unwrap_or_default();
+            let stream = StreamEntry {
+                index: info.index,
+                name,
+                volume: percent(&info.volume),
+                muted: info.mute,
+            };
+            streams.push((stream, info.volume));
+        }
+        ListResult::End => {
+            let streams = std::mem::take(&mut streams);
+            shared.update_mixer(|mixer| mixer.streams = streams);
+        }
+        ListResult::Error => warn!("Unable to list streams"),
+    });
+}
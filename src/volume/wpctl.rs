@@ -0,0 +1,161 @@
+use std::{fmt, num::ParseFloatError, time::Duration};
+
+use log::warn;
+use tokio::{
+    process::Command,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+};
+
+use super::{BackendUpdate, Device, VolumeInfo, VolumeTask};
+
+/// Polls wpctl for the volumes, refreshing immediately after each task
+pub async fn run(
+    updates: UnboundedSender<BackendUpdate>,
+    mut tasks: UnboundedReceiver<VolumeTask>,
+    poll_rate: Duration,
+) {
+    let mut interval = tokio::time::interval(poll_rate);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            task = tasks.recv() => match task {
+                Some(task) => run_task(task).await,
+                None => break,
+            },
+        }
+
+        for device in [Device::Sink, Device::Source] {
+            // Fails when there is no such device, e.g. no microphone
+            let info = get_info(device).await.ok();
+            if updates.send(BackendUpdate::Device(device, info)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn target(device: Device) -> &'static str {
+    match device {
+        Device::Sink => "@DEFAULT_AUDIO_SINK@",
+        Device::Source => "@DEFAULT_AUDIO_SOURCE@",
+    }
+}
+
+async fn get_info(device: Device) -> Result<VolumeInfo, Box<dyn std::error::Error + Send + Sync>> {
+    let output = Command::new("wpctl")
+        .arg("get-volume")
+        .arg(target(device))
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(format!("wpctl exited with {}", output.status).into());
+    }
+    let stdout = String::try_from(output.stdout)?;
+
+    let (volume, muted) = parse_volume(&stdout)?;
+    let volume = (volume * 100.0).round() as u32;
+
+    Ok(VolumeInfo::new(device, volume, muted))
+}
+
+#[derive(Debug)]
+enum ParseVolumeError {
+    MissingPrefix,
+    InvalidVolume(ParseFloatError),
+}
+
+impl fmt::Display for ParseVolumeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingPrefix => write!(f, "Expected output starting with \"Volume:\""),
+            Self::InvalidVolume(err) => write!(f, "Invalid volume: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseVolumeError {}
+
+/// Parses output like `Volume: 1.20 [MUTED]` into the volume as a fraction
+/// and whether it is muted
+fn parse_volume(output: &str) -> Result<(f32, bool), ParseVolumeError> {
+    let mut words = output
+        .trim()
+        .strip_prefix("Volume:")
+        .ok_or(ParseVolumeError::MissingPrefix)?
+        .split_whitespace();
+
+    let volume = words
+        .next()
+        .unwrap_or_default()
+        .parse::<f32>()
+        .map_err(ParseVolumeError::InvalidVolume)?;
+    let muted = words.any(|word| word == "[MUTED]");
+
+    Ok((volume, muted))
+}
+
+async fn run_task(task: VolumeTask) {
+    let mut command = Command::new("wpctl");
+    match task.clone() {
+        VolumeTask::ToggleMute(device) => command.arg("set-mute").arg(target(device)).arg("toggle"),
+        VolumeTask::ChangeVolume(device, delta, max) => command
+            .arg("set-volume")
+            .arg("--limit")
+            .arg((max / 100.0).to_string())
+            .arg(target(device))
+            .arg(format!(
+                "{}%{}",
+                delta.abs(),
+                if delta > 0.0 { "+" } else { "-" }
+            )),
+        _ => {
+            warn!("{task:?} is not supported with wpctl");
+            return;
+        }
+    };
+
+    let output = command.output().await;
+    if !output.is_ok_and(|output| output.status.success()) {
+        warn!("Unable to run {task:?} with wpctl");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume() {
+        assert_eq!(parse_volume("Volume: 0.45\n").unwrap(), (0.45, false));
+    }
+
+    #[test]
+    fn overamplified_volume() {
+        assert_eq!(parse_volume("Volume: 1.20\n").unwrap(), (1.2, false));
+    }
+
+    #[test]
+    fn muted_volume() {
+        assert_eq!(parse_volume("Volume: 0.30 [MUTED]\n").unwrap(), (0.3, true));
+    }
+
+    #[test]
+    fn missing_prefix() {
+        assert!(matches!(
+            parse_volume("Error: no such device"),
+            Err(ParseVolumeError::MissingPrefix)
+        ));
+    }
+
+    #[test]
+    fn invalid_volume() {
+        assert!(matches!(
+            parse_volume("Volume: loud"),
+            Err(ParseVolumeError::InvalidVolume(_))
+        ));
+        assert!(matches!(
+            parse_volume("Volume:"),
+            Err(ParseVolumeError::InvalidVolume(_))
+        ));
+    }
+}